@@ -15,13 +15,20 @@ use std::error::Error;
 // use terminal_size::{Width, terminal_size};
 // use regex::Regex;
 use crate::assets::*;
+use crate::engine::{DedupeEngine, SearchOptions};
+use crate::error::DbError;
+use crate::rules::{KeeperRule, Operator};
+use crate::schema::{get_schema, Feature, Schema};
+use crate::journal;
 use crate::processing::*;
+use crate::report::{Message, Stage};
+use crate::worker::Job;
 
 
 
 use serde::Deserialize;
 
-#[derive(Default, serde::Deserialize, serde::Serialize)]
+#[derive(Default, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(default)] 
 pub struct Config {
     pub search: bool,
@@ -33,6 +40,8 @@ pub struct Config {
     pub records: HashSet<FileRecord>,
     #[serde(skip)]
     pub working: bool,
+    #[serde(skip)]
+    pub progress: f32,
 }
 
 impl Config {
@@ -44,6 +53,7 @@ impl Config {
             status: String::new(),
             records: HashSet::new(),
             working: false,
+            progress: 0.0,
 
         }
     }
@@ -55,29 +65,26 @@ impl Config {
             status: String::new(),
             records: HashSet::new(),
             working: false,
+            progress: 0.0,
 
         }
     }
     
 }
 
-#[derive(Hash, Eq, PartialEq, Clone, Debug,)]
-pub struct FileRecord {
-    pub id: usize,
-    pub filename: String,
-    pub duration: String,
-}
-
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
 pub struct TemplateApp {
     total_records: usize,
 
-    column: String,
+    columns: Vec<String>,
     find: String,
     replace: String,
     dirty: bool,
+    regex: bool,
+    match_case: bool,
+    whole_word: bool,
 
     main: Config,
     group: Config,
@@ -110,14 +117,117 @@ pub struct TemplateApp {
     #[serde(skip)] // This how you opt-out of serialization of a field
     count: usize,
     #[serde(skip)] // This how you opt-out of serialization of a field
+    replace_preview: Vec<ReplaceRow>,
+    #[serde(skip)] // This how you opt-out of serialization of a field
     gather_dupes: bool,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    find_config: Config,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    undo_config: Config,
+    /// The keeper order saved for the simulator to compare against.
+    saved_order: Vec<String>,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    order_config: Config,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    simulation: Option<OrderSimulation>,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    batch_config: Config,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    batch_rules: Vec<BatchRule>,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    job: Option<Job>,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    db_error: Option<(String, DbError)>,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    schema: Schema,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    results: Vec<DuplicateGroup>,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    results_view: ResultsView,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    rule_builder: KeeperRule,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    order_check: OrderCheck,
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    new_line_error: Option<String>,
+    /// The database and what Undo would revert in it, read again after each job instead of every frame.
+    #[serde(skip)] // This how you opt-out of serialization of a field
+    last_undo: Option<(String, Option<String>)>,
 
 }    
 
 
 
 #[derive(PartialEq, serde::Serialize, Deserialize)]
-enum Panel { Duplicates, Order, OrderText, Tags, Find }
+enum Panel { Duplicates, Results, Order, OrderText, Tags, Find }
+
+/// SQLite's verdict on each keeper order line checked against the open database, so the order panels
+/// only open the database for lines they haven't seen.
+#[derive(Default)]
+struct OrderCheck {
+    db: Option<String>,
+    /// `None` for valid lines.
+    errors: HashMap<String, Option<String>>,
+    /// Why the lines could not be checked. Unchecked lines are not blocked.
+    unchecked: Option<String>,
+    /// When the text editor last changed, its lines are checked once typing pauses.
+    edited: f64,
+}
+
+/// Seconds the order text has to sit unchanged before its new lines are checked.
+const ORDER_CHECK_DELAY: f64 = 0.5;
+
+/// How the results browser filters and orders duplicate groups.
+#[derive(Default)]
+pub struct ResultsView {
+    pub filter: String,
+    pub sort: ResultSort,
+    pub descending: bool,
+    /// `None` once the groups change.
+    rows: Option<VisibleRows>,
+}
+
+/// The rows of `visible_results` with the filter and sort they were built for.
+struct VisibleRows {
+    filter: String,
+    sort: ResultSort,
+    descending: bool,
+    rows: Vec<(usize, usize)>,
+}
+
+impl ResultsView {
+    /// The (group, row) pairs to show, rebuilt only when the filter, the sort or the groups have changed.
+    pub fn rows(&mut self, groups: &[DuplicateGroup]) -> &[(usize, usize)] {
+        let current = self.rows.as_ref().is_some_and(|rows|
+            rows.filter == self.filter && rows.sort == self.sort && rows.descending == self.descending);
+        if !current {
+            let rows = visible_results(groups, self);
+            self.rows = Some(VisibleRows { filter: self.filter.clone(), sort: self.sort, descending: self.descending, rows });
+        }
+        self.rows.as_ref().map_or(&[], |visible| &visible.rows)
+    }
+
+    /// Call whenever the groups are replaced or cleared.
+    pub fn groups_changed(&mut self) {
+        self.rows = None;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum ResultSort { #[default] Filename, Pathname, Duration, Records }
+
+impl ResultSort {
+    pub const ALL: [ResultSort; 4] = [ResultSort::Filename, ResultSort::Pathname, ResultSort::Duration, ResultSort::Records];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ResultSort::Filename => "Filename",
+            ResultSort::Pathname => "Pathname",
+            ResultSort::Duration => "Duration",
+            ResultSort::Records => "Group Size",
+        }
+    }
+}
 
 
 impl Default for TemplateApp {
@@ -125,10 +235,13 @@ impl Default for TemplateApp {
         let mut app = Self {
 
             total_records: 0,
-            column: "Filepath".to_owned(),
+            columns: vec!["Filepath".to_owned()],
             find: String::new(),
             replace: String::new(),
             dirty: true,
+            regex: false,
+            match_case: true,
+            whole_word: false,
             main: Config::new(true),
             group: Config::new_option(false, "Show"),
             group_null: false,
@@ -148,10 +261,28 @@ impl Default for TemplateApp {
             help: false,
             replace_safety: false,
             count: 0,
+            replace_preview: Vec::new(),
             gather_dupes: false,
+            find_config: Config::default(),
+            undo_config: Config::default(),
+            saved_order: Vec::new(),
+            order_config: Config::default(),
+            simulation: None,
+            batch_config: Config::default(),
+            batch_rules: Vec::new(),
+            job: None,
+            db_error: None,
+            schema: Schema::default(),
+            results: Vec::new(),
+            results_view: ResultsView::default(),
+            rule_builder: KeeperRule::default(),
+            order_check: OrderCheck::default(),
+            new_line_error: None,
+            last_undo: None,
         };
         app.tags.list = default_tags();
         app.main.list = default_order();
+        app.saved_order = default_order();
 
         app
     }
@@ -166,28 +297,426 @@ impl TemplateApp {
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
         if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            let mut app: Self = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            // The database may have moved or changed since the last session
+            if let Some(path) = app.main.option.clone() {
+                app.open_database(path);
+            }
+            return app;
         }
 
         Default::default()
     }
     fn reset_to_defaults(&mut self, db_path: Option<String>) {
         *self = Self::default();
-        self.main.option = db_path;
+        if let Some(path) = db_path {
+            self.open_database(path);
+        }
+    }
+    /// Switches to `path` once it has been checked. A file that can't be opened is reported in a dialog,
+    /// leaving the current database and settings as they were unless it was the failing one.
+    fn open_database(&mut self, path: String) {
+        let opened = get_schema(&path).and_then(|schema| Ok((schema, get_db_size(&path)?, get_columns(&path)?)));
+        match opened {
+            Ok((schema, size, columns)) => {
+                self.schema = schema;
+                self.total_records = size;
+                self.group.list = columns;
+                self.main.option = Some(path);
+                self.last_undo = None;
+            }
+            Err(e) => {
+                if self.main.option.as_ref() == Some(&path) {
+                    self.main.option = None;
+                }
+                self.db_error = Some((path, e));
+            }
+        }
+    }
+    /// SQLite's error for each of `lines` against the open database, `None` for valid lines.
+    fn check_order_lines(&mut self, lines: &[String]) -> Vec<Option<String>> {
+        let check = &mut self.order_check;
+        if check.db != self.main.option || check.errors.len() > 1000 {
+            *check = OrderCheck { db: self.main.option.clone(), edited: check.edited, ..Default::default() };
+        }
+        let mut new_lines: Vec<String> = lines.iter().filter(|line| !check.errors.contains_key(*line)).cloned().collect();
+        new_lines.dedup();
+        if !new_lines.is_empty() {
+            let errors = match self.main.option.as_deref().map(|path| check_order(path, &new_lines)) {
+                Some(Ok(errors)) => errors,
+                Some(Err(e)) => {
+                    check.unchecked = Some(format!("Unable to check the order: {}", e));
+                    vec![None; new_lines.len()]
+                }
+                None => {
+                    check.unchecked = Some("Open a database to check the order".to_string());
+                    vec![None; new_lines.len()]
+                }
+            };
+            check.errors.extend(new_lines.into_iter().zip(errors));
+        }
+        lines.iter().map(|line| check.errors.get(line).cloned().flatten()).collect()
+    }
+    /// The errors of the lines checked so far, and whether any of `lines` is still waiting to be checked.
+    fn checked_order_lines(&self, lines: &[String]) -> (Vec<Option<String>>, bool) {
+        let errors = &self.order_check.errors;
+        let pending = self.order_check.db != self.main.option || lines.iter().any(|line| !errors.contains_key(line));
+        (lines.iter().map(|line| errors.get(line).cloned().flatten()).collect(), pending)
+    }
+    /// Describes the operation Undo would revert in the open database.
+    fn last_undo(&mut self) -> Option<String> {
+        let path = self.main.option.clone()?;
+        if self.last_undo.as_ref().map(|(db, _)| db) != Some(&path) {
+            let last = journal::last_operation(&path).unwrap_or_else(|e| {
+                log::warn!("Unable to read the undo journal of {}: {}", path, e);
+                None
+            });
+            self.last_undo = Some((path, last));
+        }
+        self.last_undo.as_ref().and_then(|(_, last)| last.clone())
+    }
+    fn pick_database(&mut self) {
+        if let Some(path) = open_db() {
+            self.open_database(path);
+        }
+    }
+    fn config_mut(&mut self, stage: Stage) -> &mut Config {
+        match stage {
+            Stage::Main => &mut self.main,
+            Stage::Group => &mut self.group,
+            Stage::Deep => &mut self.deep,
+            Stage::Tags => &mut self.tags,
+            Stage::Compare => &mut self.compare_db,
+            Stage::Find => &mut self.find_config,
+            Stage::Batch => &mut self.batch_config,
+            Stage::Undo => &mut self.undo_config,
+            Stage::Order => &mut self.order_config,
+        }
+    }
+
+    /// Applies everything the background job has reported since the last frame.
+    fn poll_job(&mut self) {
+        let Some(job) = &self.job else { return; };
+        let cancelled = job.is_cancelled();
+        for message in job.poll() {
+            match message {
+                Message::Status(stage, status) => self.config_mut(stage).status = status,
+                Message::Working(stage, working) => {
+                    let config = self.config_mut(stage);
+                    config.working = working;
+                    config.progress = 0.0;
+                }
+                Message::Progress(stage, progress) => self.config_mut(stage).progress = progress,
+                Message::Records(stage, records) => self.config_mut(stage).records = records,
+                Message::Results(groups) => {
+                    self.results = groups;
+                    self.results_view.groups_changed();
+                }
+                Message::Simulation(simulation) => self.simulation = Some(simulation),
+                Message::Count(count) => self.count = count,
+                Message::Preview(rows) => self.replace_preview = rows,
+                Message::BatchCounts(counts) => {
+                    for (rule, count) in self.batch_rules.iter_mut().zip(counts) {
+                        rule.count = Some(count);
+                    }
+                }
+                Message::Done => {
+                    self.job = None;
+                    // Only one job runs at a time, and one that failed may not have cleared its flags
+                    for stage in Stage::ALL {
+                        self.config_mut(stage).working = false;
+                    }
+                    if cancelled {
+                        self.count = 0;
+                        self.replace_safety = false;
+                        self.replace_preview.clear();
+                    }
+                    // Nothing is left to review once the marked records are removed
+                    if self.main.records.is_empty() {
+                        self.results.clear();
+                        self.results_view.groups_changed();
+                    }
+                    self.refresh_total_records();
+                    self.last_undo = None;
+                }
+            }
+        }
+    }
+
+    fn search_duplicates(&mut self, ctx: &egui::Context, remove: bool) {
+        if self.job.is_some() { return; }
+        let Some(path) = self.main.option.clone() else { return; };
+        for config in [&mut self.main, &mut self.group, &mut self.deep, &mut self.tags, &mut self.compare_db, &mut self.undo_config] {
+            config.records.clear();
+            config.status.clear();
+        }
+        self.results.clear();
+        self.results_view.groups_changed();
+        let engine = DedupeEngine::new(path, self.search_options());
+        let (safe, dupes_db) = (self.safe, self.dupes_db);
+
+        self.job = Some(Job::spawn(ctx, move |reporter| {
+            // Failures and cancels reach the UI as statuses
+            let Ok(report) = engine.search(reporter) else { return; };
+            if remove {
+                let _ = engine.remove(&report.marked(), safe, dupes_db, reporter);
+                return;
+            }
+            match engine.groups(&report, reporter) {
+                Ok(groups) => reporter.send(Message::Results(groups)),
+                Err(DbError::Cancelled) => {}
+                Err(e) => reporter.status(Stage::Main, format!("Failed to load results: {}", e)),
+            }
+        }));
+    }
+
+    fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            order: self.main.list.clone(),
+            filename_check: self.main.search && self.schema.supports(Feature::Duplicates),
+            group_by: if self.group.search { self.group.option.clone() } else { None },
+            group_null: self.group_null,
+            deep_dive: self.deep.search && self.schema.supports(Feature::DeepDive),
+            tags: if self.tags.search && self.schema.supports(Feature::Tags) { Some(self.tags.list.clone()) } else { None },
+            compare_db: if self.compare_db.search && self.schema.supports(Feature::Compare) { self.compare_db.option.clone() } else { None },
+            compare_match: self.compare_db.list.clone(),
+        }
+    }
+
+    fn remove_duplicates(&mut self, ctx: &egui::Context) {
+        if self.job.is_some() { return; }
+        let Some(path) = self.main.option.clone() else { return; };
+        let engine = DedupeEngine::new(path, self.search_options());
+        // Flips made in the results browser decide what goes, when the results loaded
+        let records = if self.results.is_empty() { self.main.records.clone() } else { marked_records(&self.results) };
+        let (safe, dupes_db) = (self.safe, self.dupes_db);
+
+        self.job = Some(Job::spawn(ctx, move |reporter| {
+            let _ = engine.remove(&records, safe, dupes_db, reporter);
+        }));
+    }
+
+    fn replace_count(&mut self, ctx: &egui::Context) {
+        if self.job.is_some() { return; }
+        let Some(path) = self.main.option.clone() else { return; };
+        let matcher = match self.matcher() {
+            Ok(matcher) => matcher,
+            Err(e) => {
+                self.find_config.status = format!("Invalid regular expression: {}", e);
+                self.replace_safety = false;
+                return;
+            }
+        };
+        let (find, replace, columns) = (self.find.clone(), self.replace.clone(), self.columns.clone());
+        self.replace_safety = true;
+        self.count = 0;
+        self.replace_preview.clear();
+        self.undo_config.status.clear();
+        self.find_config.working = true;
+
+        self.job = Some(Job::spawn(ctx, move |reporter| {
+            reporter.working(Stage::Find, true);
+            reporter.status(Stage::Find, format!("Searching {} for '{}'", columns.join(", "), find));
+            match smreplace_get(&path, &matcher, &replace, &columns, reporter) {
+                _ if reporter.is_cancelled() => reporter.status(Stage::Find, "Search cancelled"),
+                Ok(rows) => {
+                    reporter.send(Message::Preview(rows));
+                    reporter.status(Stage::Find, "");
+                }
+                Err(e) => reporter.status(Stage::Find, format!("Search failed: {}", e)),
+            }
+            reporter.working(Stage::Find, false);
+        }));
+    }
+
+    fn replace_process(&mut self, ctx: &egui::Context) {
+        if self.job.is_some() { return; }
+        let Some(path) = self.main.option.clone() else { return; };
+        let rows = std::mem::take(&mut self.replace_preview);
+        let (find, replace, dirty) = (self.find.clone(), self.replace.clone(), self.dirty && self.schema.supports(Feature::Dirty));
+        let columns = replace_counts(&rows).into_iter().map(|(column, _)| column).collect::<Vec<_>>().join(", ");
+        self.replace_safety = false;
+        self.find_config.working = true;
+
+        self.job = Some(Job::spawn(ctx, move |reporter| {
+            reporter.working(Stage::Find, true);
+            reporter.status(Stage::Find, format!("Replacing '{}' with '{}' in {}", find, replace, columns));
+            match smreplace_process(&path, &rows, dirty, reporter) {
+                Ok(count) => {
+                    reporter.send(Message::Count(count));
+                    reporter.status(Stage::Find, "");
+                }
+                Err(e) => {
+                    reporter.send(Message::Count(0));
+                    if let DbError::Cancelled = e {
+                        reporter.status(Stage::Find, "Replace cancelled. No records were changed.");
+                    } else {
+                        reporter.status(Stage::Find, format!("Replace failed: {}", e));
+                    }
+                }
+            }
+            reporter.working(Stage::Find, false);
+        }));
+    }
+
+    fn load_batch(&mut self) {
+        let Some(path) = rfd::FileDialog::new().add_filter("CSV", &["csv"]).pick_file() else { return; };
+        let path = path.display().to_string();
+        self.batch_rules.clear();
+        self.batch_config.status = match fs::read_to_string(&path) {
+            Ok(text) => match parse_batch_csv(&text) {
+                Ok(rules) => {
+                    self.batch_rules = rules;
+                    format!("Loaded {} rules", self.batch_rules.len())
+                }
+                Err(e) => format!("Failed to read {}: {}", path, e),
+            },
+            Err(e) => format!("Failed to open {}: {}", path, e),
+        };
+        self.batch_config.option = Some(path);
+    }
+
+    /// Pairs each rule with its matcher, using the panel's Match Case and Whole Word options.
+    fn batch_matchers(&self) -> Result<Vec<(BatchRule, Matcher)>, String> {
+        self.batch_rules.iter()
+            .map(|rule| {
+                Matcher::new(&rule.find, rule.regex, self.match_case, self.whole_word)
+                    .map(|matcher| (rule.clone(), matcher))
+                    .map_err(|e| format!("Line {}: invalid regular expression: {}", rule.line, e))
+            })
+            .collect()
+    }
+
+    fn batch_count(&mut self, ctx: &egui::Context) {
+        if self.job.is_some() { return; }
+        let Some(path) = self.main.option.clone() else { return; };
+        let rules = match self.batch_matchers() {
+            Ok(rules) => rules,
+            Err(e) => {
+                self.batch_config.status = e;
+                return;
+            }
+        };
+        self.batch_rules.iter_mut().for_each(|rule| rule.count = None);
+        self.batch_config.working = true;
+
+        self.job = Some(Job::spawn(ctx, move |reporter| {
+            reporter.working(Stage::Batch, true);
+            reporter.status(Stage::Batch, format!("Counting matches for {} rules", rules.len()));
+            match smreplace_batch_counts(&path, &rules, reporter) {
+                _ if reporter.is_cancelled() => reporter.status(Stage::Batch, "Count cancelled"),
+                Ok(counts) => {
+                    reporter.status(Stage::Batch, format!("{} matches across {} rules", counts.iter().sum::<usize>(), counts.len()));
+                    reporter.send(Message::BatchCounts(counts));
+                }
+                Err(e) => reporter.status(Stage::Batch, format!("Count failed: {}", e)),
+            }
+            reporter.working(Stage::Batch, false);
+        }));
+    }
+
+    fn batch_apply(&mut self, ctx: &egui::Context) {
+        if self.job.is_some() { return; }
+        let Some(path) = self.main.option.clone() else { return; };
+        let Ok(rules) = self.batch_matchers() else { return; };
+        let dirty = self.dirty && self.schema.supports(Feature::Dirty);
+        self.batch_rules.iter_mut().for_each(|rule| rule.count = None);
+        self.undo_config.status.clear();
+        self.batch_config.working = true;
+
+        self.job = Some(Job::spawn(ctx, move |reporter| {
+            reporter.working(Stage::Batch, true);
+            reporter.status(Stage::Batch, format!("Applying {} rules", rules.len()));
+            match smreplace_batch_process(&path, &rules, dirty, reporter) {
+                Ok(count) => reporter.status(Stage::Batch, format!("Applied {} rules, {} values replaced", rules.len(), count)),
+                Err(DbError::Cancelled) => reporter.status(Stage::Batch, "Batch cancelled. No records were changed."),
+                Err(e) => reporter.status(Stage::Batch, format!("Batch failed: {}", e)),
+            }
+            reporter.working(Stage::Batch, false);
+        }));
+    }
+
+    /// Ranks the duplicates with the saved and the current keeper order and reports the groups whose keeper changes.
+    fn simulate_order(&mut self, ctx: &egui::Context) {
+        if self.job.is_some() { return; }
+        let Some(path) = self.main.option.clone() else { return; };
+        self.simulation = None;
+        self.order_config.working = true;
+        let engine = DedupeEngine::new(path, self.search_options());
+        let saved = self.saved_order.clone();
+
+        self.job = Some(Job::spawn(ctx, move |reporter| {
+            if let Ok(simulation) = engine.simulate(&saved, reporter) {
+                reporter.send(Message::Simulation(simulation));
+            }
+        }));
+    }
+
+    fn undo_last(&mut self, ctx: &egui::Context) {
+        if self.job.is_some() { return; }
+        let Some(path) = self.main.option.clone() else { return; };
+        self.replace_safety = false;
+        self.replace_preview.clear();
+        self.undo_config.working = true;
+
+        self.job = Some(Job::spawn(ctx, move |reporter| {
+            reporter.working(Stage::Undo, true);
+            reporter.status(Stage::Undo, "Undoing last operation");
+            match journal::undo_last(&path, reporter) {
+                Ok(Some(description)) => reporter.status(Stage::Undo, format!("Undone: {}", description)),
+                Ok(None) => reporter.status(Stage::Undo, "Nothing to undo"),
+                Err(DbError::Cancelled) => reporter.status(Stage::Undo, "Undo cancelled. No records were changed."),
+                Err(e) => reporter.status(Stage::Undo, format!("Undo failed: {}", e)),
+            }
+            reporter.working(Stage::Undo, false);
+        }));
+    }
+
+    /// Offers to pick another file when a database could not be opened, keeping the rest of the session.
+    fn db_error_dialog(&mut self, ctx: &egui::Context) {
+        let Some((path, error)) = &self.db_error else { return; };
+        let (mut choose, mut dismiss) = (false, false);
+        egui::Window::new("Unable to open database")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(RichText::new(display_name(path)).strong());
+                ui.label(error.to_string());
+                ui.separator();
+                ui.horizontal(|ui| {
+                    choose = ui.button("Choose Another File").clicked();
+                    dismiss = ui.button("Dismiss").clicked();
+                });
+            });
+        if choose || dismiss {
+            self.db_error = None;
+        }
+        if choose {
+            self.pick_database();
+        }
+    }
+
+    fn matcher(&self) -> Result<Matcher, regex::Error> {
+        Matcher::new(&self.find, self.regex, self.match_case, self.whole_word)
+    }
+
+    fn refresh_total_records(&mut self) {
         if let Some(path) = self.main.option.clone() {
-            self.total_records = get_db_size(path.clone());
-            self.group.list = get_columns(path.clone());
+            match get_db_size(&path) {
+                Ok(size) => self.total_records = size,
+                Err(e) => self.db_error = Some((path, e)),
+            }
         }
     }
     fn reset_to_TJFdefaults(&mut self, db_path: Option<String>) {
         *self = Self::default();
-        self.main.option = db_path;
         self.main.list = tjf_order();
+        self.saved_order = tjf_order();
         self.tags.list = tjf_tags();
         self.tags.list = tjf_tags();
-        if let Some(path) = self.main.option.clone() {
-            self.total_records = get_db_size(path.clone());
-            self.group.list = get_columns(path.clone());
+        if let Some(path) = db_path {
+            self.open_database(path);
         }
     }
 }
@@ -204,6 +733,7 @@ impl eframe::App for TemplateApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
+        self.poll_job();
         
         
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
@@ -216,14 +746,18 @@ impl eframe::App for TemplateApp {
                     ui.menu_button("File", |ui| {
                         if ui.button("Open Database").clicked() {
                             ui.close_menu();
-                            self.main.option = open_db();
-                            if let Some(path) = self.main.option.clone() {
-                                self.total_records = get_db_size(path.clone());
-                                self.group.list = get_columns(path.clone());
-                            }
+                            self.pick_database();
                         }
                         if ui.button("Close Database").clicked() {ui.close_menu(); self.main.option = None;}
                         ui.separator();
+                        let undo = self.last_undo();
+                        let can_undo = self.job.is_none() && undo.is_some();
+                        let hover = undo.unwrap_or_else(|| "Nothing to undo".to_string());
+                        if ui.add_enabled(can_undo, egui::Button::new("Undo last operation"))
+                            .on_hover_text(&hover)
+                            .on_disabled_hover_text(&hover)
+                            .clicked() {ui.close_menu(); self.undo_last(ctx);}
+                        ui.separator();
                         if ui.button("Restore Defaults").clicked() {ui.close_menu(); self.reset_to_defaults(self.main.option.clone())}
                         if  ui.input(|i| i.modifiers.alt ) {
                             if ui.button("TJF Defaults").clicked() {ui.close_menu(); self.reset_to_TJFdefaults(self.main.option.clone())}
@@ -243,6 +777,7 @@ impl eframe::App for TemplateApp {
                     // ui.add_space(16.0);
                     ui.menu_button("View", |ui| {
                         if ui.button("Duplicates Search").clicked() {ui.close_menu(); self.my_panel = Panel::Duplicates}
+                        if ui.button("Duplicate Results").clicked() {ui.close_menu(); self.my_panel = Panel::Results}
                         if ui.button("Find & Replace").clicked() {ui.close_menu(); self.my_panel = Panel::Find}
                         ui.separator();
                         if ui.button("Duplicate Search Logic").clicked() {ui.close_menu(); self.my_panel = Panel::Order}
@@ -266,6 +801,10 @@ impl eframe::App for TemplateApp {
                         ui.selectable_value(&mut self.my_panel, Panel::Duplicates, RichText::new("Duplicate Filename Search"),);
                         
                         ui.add_space(16.0);
+                        if !self.results.is_empty() {
+                            ui.selectable_value(&mut self.my_panel, Panel::Results, "Results",);
+                            ui.add_space(16.0);
+                        }
                         // ui.selectable_value(&mut self.my_panel, Panel::Order, "Adjust Search Order Config",);
                         // ui.add_space(16.0);
                         // ui.selectable_value(&mut self.my_panel, Panel::Tags, "Manage Audiosuite Tags",);
@@ -289,24 +828,28 @@ impl eframe::App for TemplateApp {
                     // ui.horizontal_centered(|ui| {
                         ui.vertical_centered(|ui| {
                             if ui.add_sized([200.0, 50.0], egui::Button::new(RichText::new("Open Database").size(24.0).strong())).clicked() {
-                                self.main.option = open_db();
-                                if let Some(path) = self.main.option.clone() {
-                                    self.total_records = get_db_size(path.clone());
-                                    self.group.list = get_columns(path.clone());
-                                }
+                                self.pick_database();
                             } 
                         });
        
                     return;
                 }
                 ui.horizontal(|_| {});
-                let mut db_name = "";
+                let mut db_name = String::new();
                 ui.vertical_centered(|ui| {
                     if let Some(path) = &self.main.option {
-                        db_name = path.split('/').last().unwrap();
-                        ui.heading(RichText::new(path.split('/').last().unwrap()).size(24.0).strong().extra_letter_spacing(5.0));
+                        db_name = display_name(path).to_string();
+                        ui.heading(RichText::new(display_name(path)).size(24.0).strong().extra_letter_spacing(5.0));
                     }
                     ui.label(format!("{} records", self.total_records));
+                    let missing = self.schema.missing_columns();
+                    if !missing.is_empty() {
+                        let disabled: Vec<&str> = self.schema.disabled().into_iter().map(Feature::name).collect();
+                        ui.colored_label(ui.visuals().warn_fg_color, format!("Missing columns: {}. Disabled: {}", missing.join(", "), disabled.join(", ")));
+                    }
+                    if self.undo_config.working || !self.undo_config.status.is_empty() {
+                        status_line(ui, &self.undo_config);
+                    }
                     
                 });
                 ui.horizontal(|_| {});
@@ -317,8 +860,11 @@ impl eframe::App for TemplateApp {
             match self.my_panel {
                 Panel::Find => {
                     ui.heading("Find and Replace");
-                    ui.label("Note: Search is Case Sensitive");
                     ui.separator();
+                    if let Err(e) = self.schema.require(Feature::Replace) {
+                        ui.label(e.to_string());
+                        return;
+                    }
                     ui.horizontal(|ui| {
                         ui.label("Find Text: ");
                         ui.text_edit_singleline(&mut self.find);
@@ -330,59 +876,113 @@ impl eframe::App for TemplateApp {
                         ui.text_edit_singleline(&mut self.replace);
                     });
                     ui.horizontal(|ui| {
-                        ui.label("in Column: ");
-                        combo_box(ui, "find_column", &mut self.column, &self.group.list);
+                        ui.checkbox(&mut self.match_case, "Match Case");
+                        ui.checkbox(&mut self.whole_word, "Whole Word");
+                        ui.checkbox(&mut self.regex, "Regular Expression");
+                        if self.regex {
+                            ui.label("Use $1, $2... in Replace to insert capture groups");
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("in Columns: ");
+                        multi_combo_box(ui, "find_column", &mut self.columns, &self.group.list);
                     });
                     ui.separator();
-                    ui.checkbox(&mut self.dirty,"Mark Records as Dirty?");
+                    feature_checkbox(ui, &self.schema, Feature::Dirty, &mut self.dirty, "Mark Records as Dirty?");
                     ui.label("Dirty Records are audio files with metadata that is not embedded");
                     ui.separator();
+                    egui::CollapsingHeader::new("Batch Replace from CSV").show(ui, |ui| {
+                        ui.label("One rule per line: column,find,replace with an optional fourth regex column of true or false");
+                        ui.horizontal(|ui| {
+                            if ui.add_enabled(self.job.is_none(), egui::Button::new("Load CSV")).clicked() {
+                                self.load_batch();
+                            }
+                            if let Some(path) = &self.batch_config.option {
+                                ui.label(display_name(path));
+                            }
+                        });
+                        if !self.batch_rules.is_empty() {
+                            batch_table(ui, &self.batch_rules);
+                            ui.horizontal(|ui| {
+                                if ui.add_enabled(self.job.is_none(), egui::Button::new("Count Matches")).clicked() {
+                                    self.batch_count(ctx);
+                                }
+                                let counted = self.batch_rules.iter().all(|rule| rule.count.is_some());
+                                if ui.add_enabled(self.job.is_none() && counted, egui::Button::new("Apply All")).clicked() {
+                                    self.batch_apply(ctx);
+                                }
+                            });
+                            ui.label("Rules are applied in order, a batch can be reverted with File > Undo last operation");
+                        }
+                        status_line(ui, &self.batch_config);
+                        if self.batch_config.working {
+                            if let Some(job) = &self.job {
+                                if ui.button("Cancel").clicked() { job.cancel(); }
+                            }
+                        }
+                    });
+                    ui.separator();
         
-                    if self.find.is_empty() {
+                    if self.find.is_empty() || self.columns.is_empty() {
                         return;
                     }
-                    if ui.button("Process").clicked() {
-                        if let Some(path) = &self.main.option {
-                            self.replace_safety = true;
-                            self.count = smreplace_get(path.clone(), &mut self.find,  &mut self.column);
-
+                    if ui.add_enabled(self.job.is_none(), egui::Button::new("Process")).clicked() {
+                        self.replace_count(ctx);
+                    }
+                    if self.find_config.working {
+                        status_line(ui, &self.find_config);
+                        if let Some(job) = &self.job {
+                            if ui.button("Cancel").clicked() { job.cancel(); }
                         }
-                        
-                        
                     }
-                    if self.replace_safety {
+                    else if !self.find_config.status.is_empty() {
+                        ui.label(self.find_config.status.clone());
+                    }
+                    else if self.replace_safety {
                         // if let Some(path) = &self.main.option {
                         //     self.count = smreplace_get(path.clone(), &mut self.find,  &mut self.column);
 
                         // }
-                        ui.label(format!("Found {} records matching '{}' in {} of SM database: {}", self.count, self.find, self.column, db_name));
-                        if self.count == 0 {return;}
-                        ui.label(format!("Replace with '{}'?", self.replace));
-                        ui.label(format!("This is NOT undoable"));
+                        ui.label(format!("Found {} matches for '{}' in {} of SM database: {}", self.replace_preview.len(), self.find, self.columns.join(", "), db_name));
+                        if self.replace_preview.is_empty() {return;}
+                        ui.horizontal(|ui| {
+                            if ui.button("Include All").clicked() {
+                                self.replace_preview.iter_mut().for_each(|row| row.include = true);
+                            }
+                            if ui.button("Include None").clicked() {
+                                self.replace_preview.iter_mut().for_each(|row| row.include = false);
+                            }
+                        });
+                        replace_preview_table(ui, &mut self.replace_preview);
+                        let counts = replace_counts(&self.replace_preview);
+                        for (column, count) in &counts {
+                            ui.label(format!("{}: {} values", column, count));
+                        }
+                        let included: usize = counts.iter().map(|(_, count)| count).sum();
+                        ui.label(format!("Replace with '{}' in {} of {} values?", self.replace, included, self.replace_preview.len()));
+                        ui.label("This can be reverted with File > Undo last operation");
                         ui.separator();
                         ui.horizontal(|ui| {
 
-                            if ui.button("Proceed").clicked() {
-                                if let Some(path) = &self.main.option {
-                                    smreplace_process(path.clone(), &mut self.find, &mut self.replace, &mut self.column, self.dirty);
-                                }
-                                self.replace_safety = false;
+                            if ui.add_enabled(included > 0, egui::Button::new("Proceed")).clicked() {
+                                self.replace_process(ctx);
                             }
                             if ui.button("Cancel").clicked() {
                                 self.count = 0;
                                 self.replace_safety = false;
+                                self.replace_preview.clear();
                             }
                         });
                     }
                     else if self.count > 0 {
-                        ui.label(format!("{} records replaced", self.count));
+                        ui.label(format!("{} values replaced", self.count));
                     }
                         
                 }
                 Panel::Duplicates => {
                     ui.heading("Search for Duplicate Records");
         
-                    ui.checkbox(&mut self.main.search, "Basic Duplicate Filename Search");
+                    feature_checkbox(ui, &self.schema, Feature::Duplicates, &mut self.main.search, "Basic Duplicate Filename Search");
                         
                         //GROUP GROUP GROUP GROUP
                         ui.horizontal(|ui| {
@@ -404,27 +1004,19 @@ impl eframe::App for TemplateApp {
                             ui.radio_value(&mut self.group_null, true, "Process Together");
                             // ui.checkbox(&mut self.group_null, "Process records without defined group together, or skip?");
                         });
-                        ui.horizontal( |ui| {
-                            if self.group.working {ui.spinner();}
-                            ui.label(self.group.status.clone());
-
-                        });
+                        status_line(ui, &self.group);
 
                         //DEEP DIVE DEEP DIVE DEEP DIVE
-                        ui.checkbox(&mut self.deep.search, "Deep Dive Duplicates Search (Slow)");
+                        feature_checkbox(ui, &self.schema, Feature::DeepDive, &mut self.deep.search, "Deep Dive Duplicates Search (Slow)");
                         ui.horizontal( |ui| {
                             ui.add_space(24.0);
                             ui.label("Filenames ending in .#, .#.#.#, or .M will be examined as possible duplicates");
                         });
-                        ui.horizontal( |ui| {
-                            if self.deep.working {ui.spinner();}
-                            ui.label(self.deep.status.clone());
-
-                        });
+                        status_line(ui, &self.deep);
                         ui.separator();
 
                     //TAGS TAGS TAGS TAGS
-                    ui.checkbox(&mut self.tags.search, "Search for Records with AudioSuite Tags");
+                    feature_checkbox(ui, &self.schema, Feature::Tags, &mut self.tags.search, "Search for Records with AudioSuite Tags");
 
 
                         ui.horizontal(|ui| {
@@ -432,18 +1024,14 @@ impl eframe::App for TemplateApp {
                             ui.label("Filenames with Common Protools AudioSuite Tags will be marked for removal")
                         });
                         
-                        ui.horizontal(|ui| {
-                            if self.tags.working {ui.spinner();}
-                            ui.label(self.tags.status.clone());
-
-                        });
+                        status_line(ui, &self.tags);
                         ui.separator();
 
                     //COMPARE COMPARE COMPARE COMPARE
                     ui.horizontal(|ui| {
-                        ui.checkbox(&mut self.compare_db.search, "Compare against database: ");
+                        feature_checkbox(ui, &self.schema, Feature::Compare, &mut self.compare_db.search, "Compare against database: ");
                         if let Some(path) = &self.compare_db.option {
-                            ui.label(path.split('/').last().unwrap());
+                            ui.label(display_name(path));
                         }
 
                         
@@ -458,7 +1046,12 @@ impl eframe::App for TemplateApp {
                             ui.add_space(24.0);
                             ui.label("Filenames from Target Database found in Comparison Database will be Marked for Removal");
                         });
-                        ui.label(self.compare_db.status.clone());
+                        ui.horizontal(|ui| {
+                            ui.add_space(24.0);
+                            ui.label("Also match: ");
+                            multi_combo_box(ui, "compare_match", &mut self.compare_db.list, &self.group.list);
+                        });
+                        status_line(ui, &self.compare_db);
                         ui.separator();
 
                     ui.horizontal(|_| {});
@@ -469,35 +1062,26 @@ impl eframe::App for TemplateApp {
                     ui.horizontal( |ui| {});
                     
                     ui.horizontal(|ui| {
-                        if  ui.input(|i| i.modifiers.alt ) {
-                            if ui.button("Search and Remove Duplicates").clicked() {}
-                        } else {
-                            if ui.button("Search for Duplicates").clicked() {
-                                // self.gather_dupes = true;
-                                gather_duplicates(&mut self.main, &mut self.group, &mut self.deep, &mut self.tags, &mut self.compare_db);
+                        ui.add_enabled_ui(self.job.is_none(), |ui| {
+                            if  ui.input(|i| i.modifiers.alt ) {
+                                if ui.button("Search and Remove Duplicates").clicked() {
+                                    self.search_duplicates(ctx, true);
+                                }
+                            } else if ui.button("Search for Duplicates").clicked() {
+                                self.search_duplicates(ctx, false);
                             }
-
-                        }
-                        if self.main.records.len() > 0 {
-
-                            // button(ui, "Remove Duplicates", remove_duplicates);
-
-                            if ui.button("Remove Duplicates").clicked() {
-                                remove_duplicates();
+                            if !self.main.records.is_empty() && ui.button("Remove Duplicates").clicked() {
+                                self.remove_duplicates(ctx);
+                            }
+                            if !self.results.is_empty() && ui.button("Review Results").clicked() {
+                                self.my_panel = Panel::Results;
                             }
+                        });
+                        if let Some(job) = &self.job {
+                            if ui.button("Cancel").clicked() { job.cancel(); }
                         }
                     });
-                    ui.horizontal( |ui| {
-                        if self.main.working {ui.spinner();}
-                        ui.label(self.main.status.clone());
-
-                    });
-                    if self.main.working{
-                        ui.add( egui::ProgressBar::new(0.0)
-                                // .text("progress")
-                                .desired_height(4.0)
-                            );
-                    }
+                    status_line(ui, &self.main);
 
                     // if self.gather_dupes {
                     //     let source_db_path = self.main.option.as_ref().unwrap().clone();
@@ -518,18 +1102,74 @@ impl eframe::App for TemplateApp {
                     // }
 
 
+                }
+                Panel::Results => {
+                    ui.heading("Duplicate Results");
+                    if self.results.is_empty() {
+                        ui.label("Search for duplicates to review the marked records here");
+                        return;
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Filter: ");
+                        ui.text_edit_singleline(&mut self.results_view.filter);
+                        ui.label("Sort by: ");
+                        egui::ComboBox::from_id_source("results_sort")
+                            .selected_text(self.results_view.sort.name())
+                            .show_ui(ui, |ui| {
+                                for sort in ResultSort::ALL {
+                                    ui.selectable_value(&mut self.results_view.sort, sort, sort.name());
+                                }
+                            });
+                        ui.checkbox(&mut self.results_view.descending, "Descending");
+                    });
+                    let marked = marked_records(&self.results);
+                    ui.label(format!("{} groups, {} records marked for removal. Untick a record to keep it, tick a keeper to remove it.", self.results.len(), marked.len()));
+                    let unkept = self.results.iter()
+                        .filter(|group| group.rows[0].keeper && group.rows.iter().all(|row| row.remove))
+                        .count();
+                    if unkept > 0 {
+                        ui.colored_label(ui.visuals().warn_fg_color, format!("{} groups will keep no copy of their file", unkept));
+                    }
+                    let visible = self.results_view.rows(&self.results);
+                    results_table(ui, &mut self.results, visible);
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        if ui.add_enabled(self.job.is_none() && !marked.is_empty(), egui::Button::new(format!("Remove {} Records", marked.len()))).clicked() {
+                            self.remove_duplicates(ctx);
+                        }
+                        if let Some(job) = &self.job {
+                            if ui.button("Cancel").clicked() { job.cancel(); }
+                        }
+                    });
+                    status_line(ui, &self.main);
                 }
                 Panel::Order => {
                     if self.help {order_help(ui)}
                     
+                    let errors = self.check_order_lines(&self.main.list.clone());
                     for (index, line) in self.main.list.iter_mut().enumerate() {
                         let checked = self.sel_line == Some(index);
-                        if ui.selectable_label(checked, line.clone()).clicked {
+                        let rule = KeeperRule::parse(line);
+                        let mut text = RichText::new(rule.as_ref().map(KeeperRule::describe).unwrap_or_else(|| line.clone()));
+                        let mut hover = line.clone();
+                        if let Some(error) = &errors[index] {
+                            text = text.color(ui.visuals().error_fg_color);
+                            hover = format!("{}\n{}", line, error);
+                        }
+                        if ui.selectable_label(checked, text).on_hover_text(hover).clicked {
                             self.sel_line = if checked { None } else { Some(index) };
+                            if let Some(rule) = rule.filter(|_| !checked) {
+                                self.rule_builder = rule;
+                            }
                         }
                     }
+                    let invalid = errors.iter().flatten().count();
+                    if invalid > 0 {
+                        ui.colored_label(ui.visuals().error_fg_color, format!("{} lines are invalid and will fail the search. Hover them for details", invalid));
+                    }
                     ui.separator();
 
+                    rule_builder(ui, self);
                     order_toolbar(ui,self);
 
                     ui.separator();
@@ -537,19 +1177,84 @@ impl eframe::App for TemplateApp {
                         self.order_text = self.main.list.join("\n");
                         self.my_panel = Panel::OrderText;
                     }
-                    
+
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        let valid = errors.iter().all(Option::is_none);
+                        if ui.add_enabled(self.job.is_none() && self.main.option.is_some() && valid, egui::Button::new("Simulate"))
+                            .on_hover_text("Compare the records kept with this order against the saved order. Nothing is marked or removed")
+                            .clicked() {
+                            self.simulate_order(ctx);
+                        }
+                        if ui.button("Save Order for Comparison").clicked() {
+                            self.saved_order = self.main.list.clone();
+                            self.order_config.status.clear();
+                            self.simulation = None;
+                        }
+                        if let Some(job) = self.job.as_ref().filter(|_| self.order_config.working) {
+                            if ui.button("Cancel").clicked() { job.cancel(); }
+                        }
+                    });
+                    if self.saved_order == self.main.list {
+                        ui.label(RichText::new("The current order matches the saved order").weak());
+                    }
+                    status_line(ui, &self.order_config);
+                    if let Some(simulation) = self.simulation.as_ref().filter(|simulation| !simulation.sample.is_empty()) {
+                        simulation_table(ui, simulation);
+                    }
                 }
 
                 Panel:: OrderText => {
                     if self.help {order_help(ui)}
 
+                    let lines: Vec<String> = self.order_text.lines().map(|s| s.to_string()).collect();
+                    // Blank lines are dropped on save, so only the others need to be valid
+                    let filled: Vec<String> = lines.iter().filter(|line| !line.trim().is_empty()).cloned().collect();
+                    let now = ui.input(|i| i.time);
+                    let waited = now - self.order_check.edited;
+                    let (_, mut pending) = self.checked_order_lines(&filled);
+                    if pending && waited >= ORDER_CHECK_DELAY {
+                        self.check_order_lines(&filled);
+                        pending = false;
+                    } else if pending {
+                        ctx.request_repaint_after(std::time::Duration::from_secs_f64(ORDER_CHECK_DELAY - waited));
+                    }
+                    let errors: Vec<Option<String>> = self.checked_order_lines(&lines).0.into_iter()
+                        .zip(&lines)
+                        .map(|(error, line)| error.filter(|_| !line.trim().is_empty()))
+                        .collect();
+                    let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+                        let font_id = egui::FontSelection::default().resolve(ui.style());
+                        let mut job = egui::text::LayoutJob::default();
+                        for (index, line) in text.split_inclusive('\n').enumerate() {
+                            let color = match errors.get(index) {
+                                Some(Some(_)) => ui.visuals().error_fg_color,
+                                _ => ui.visuals().text_color(),
+                            };
+                            job.append(line, 0.0, egui::TextFormat::simple(font_id.clone(), color));
+                        }
+                        job.wrap.max_width = wrap_width;
+                        ui.fonts(|fonts| fonts.layout_job(job))
+                    };
                     ui.columns(1, |columns| {
                         // columns[0].heading("Duplicate Filename Keeper Priority Order:");
-                        columns[0].text_edit_multiline(&mut self.order_text);
+                        if columns[0].add(egui::TextEdit::multiline(&mut self.order_text).layouter(&mut layouter)).changed() {
+                            self.order_check.edited = now;
+                        }
                     });
+                    for (index, error) in errors.iter().enumerate() {
+                        if let Some(error) = error {
+                            ui.colored_label(ui.visuals().error_fg_color, format!("Line {}: {}", index + 1, error));
+                        }
+                    }
+                    if let Some(unchecked) = &self.order_check.unchecked {
+                        ui.label(RichText::new(unchecked).weak());
+                    }
                     ui.separator();
-                    if ui.button("Save").clicked() {
-                        self.main.list = self.order_text.lines().map(|s| s.to_string()).collect();
+                    let valid = errors.iter().all(Option::is_none);
+                    let hover = if pending { "Checking the order" } else { "Fix the invalid lines first" };
+                    if ui.add_enabled(valid && !pending, egui::Button::new("Save")).on_disabled_hover_text(hover).clicked() {
+                        self.main.list = lines.into_iter().filter(|line| !line.trim().is_empty()).collect();
                         self.my_panel = Panel::Order;
                     }
                 }
@@ -626,9 +1331,55 @@ impl eframe::App for TemplateApp {
             }
 
         });
+        self.db_error_dialog(ctx);
     }
 }
 
+pub fn open_db() -> Option<String> {
+    if let Some(path) = rfd::FileDialog::new().pick_file() {
+        let db_path = path.display().to_string();
+        if db_path.ends_with(".sqlite") {return Some(db_path);}
+    }
+    None
+}
+
+/// Builds a keeper order line from a column, an operator and a value, for users who don't write SQL.
+pub fn rule_builder(ui: &mut egui::Ui, app: &mut TemplateApp) {
+    let rule = &mut app.rule_builder;
+    ui.horizontal(|ui| {
+        ui.label("Rule: ");
+        combo_box(ui, "rule_column", &mut rule.column, &app.group.list);
+        egui::ComboBox::from_id_source("rule_operator")
+            .selected_text(rule.operator.name())
+            .show_ui(ui, |ui| {
+                for operator in Operator::ALL {
+                    ui.selectable_value(&mut rule.operator, operator, operator.name());
+                }
+            });
+        if rule.operator.takes_value() {
+            ui.text_edit_singleline(&mut rule.value);
+        }
+        ui.radio_value(&mut rule.prefer, true, "Prefer");
+        ui.radio_value(&mut rule.prefer, false, "Avoid");
+    });
+    let line = rule.compile();
+    // Greater and less sort by the column itself without a value, the text operators need one
+    let needs_value = matches!(rule.operator, Operator::Contains | Operator::NotContains);
+    let complete = !rule.column.is_empty() && (!needs_value || !rule.value.is_empty());
+    ui.horizontal(|ui| {
+        if ui.add_enabled(complete, egui::Button::new("Add Rule")).clicked() {
+            app.main.list.insert(0, line.clone());
+            app.sel_line = None;
+        }
+        if let Some(index) = app.sel_line {
+            if ui.add_enabled(complete, egui::Button::new("Update Selected")).clicked() {
+                app.main.list[index] = line.clone();
+            }
+        }
+        ui.label(RichText::new(&line).monospace().weak());
+    });
+}
+
 pub fn order_toolbar(ui: &mut egui::Ui, app: &mut TemplateApp) {
     ui.horizontal(|ui| {
         if ui.button("Up").clicked() {
@@ -656,11 +1407,19 @@ pub fn order_toolbar(ui: &mut egui::Ui, app: &mut TemplateApp) {
         if ui.button("Add Line:").clicked {
             
             if app.new_line.len() > 0 {
-                app.main.list.insert(0, app.new_line.clone());
-                app.new_line.clear();
+                app.new_line_error = app.check_order_lines(&[app.new_line.clone()]).remove(0);
+                if app.new_line_error.is_none() {
+                    app.main.list.insert(0, app.new_line.clone());
+                    app.new_line.clear();
+                }
         }}
-        ui.text_edit_singleline(&mut app.new_line);    
+        if ui.text_edit_singleline(&mut app.new_line).changed() {
+            app.new_line_error = None;
+        }
         if ui.button("Help").clicked {app.help = !app.help}
     });
+    if let Some(error) = &app.new_line_error {
+        ui.colored_label(ui.visuals().error_fg_color, format!("Line not added: {}", error));
+    }
 }
 
@@ -1,5 +1,7 @@
 use eframe::egui::{self, Ui, RichText};
 use crate::app::*;
+use crate::processing::{BatchRule, DuplicateGroup, OrderSimulation, ReplaceRow, ResultRow};
+use crate::schema::{Feature, Schema};
 
 // A reusable button component that takes a function (callback) to run when clicked
 pub fn button<F>(ui: &mut Ui, label: &str, action: F)
@@ -32,9 +34,218 @@ pub fn combo_box(ui: &mut Ui, label: &str, selected: &mut String, list: &Vec<Str
         });
 }
 
+pub fn status_line(ui: &mut Ui, config: &Config) {
+    ui.horizontal(|ui| {
+        if config.working {ui.spinner();}
+        ui.label(config.status.clone());
+    });
+    if config.working {
+        ui.add(egui::ProgressBar::new(config.progress).desired_height(4.0));
+    }
+}
+
+/// The sample of groups whose keeper changes between the saved and the current order.
+pub fn simulation_table(ui: &mut Ui, simulation: &OrderSimulation) {
+    egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+        egui::Grid::new("Simulation Grid")
+            .num_columns(3)
+            .striped(true)
+            .spacing([20.0, 4.0])
+            .show(ui, |ui| {
+                ui.strong("Filename");
+                ui.strong("Saved order keeps");
+                ui.strong("Current order keeps");
+                ui.end_row();
+                for change in &simulation.sample {
+                    ui.label(&change.filename);
+                    ui.label(&change.before.1).on_hover_text(format!("rowid {}", change.before.0));
+                    ui.label(&change.after.1).on_hover_text(format!("rowid {}", change.after.0));
+                    ui.end_row();
+                }
+            });
+    });
+    if simulation.changed > simulation.sample.len() {
+        ui.label(RichText::new(format!("and {} more", simulation.changed - simulation.sample.len())).weak());
+    }
+}
+
+/// A checkbox for a feature the open database may not support. It shows as unchecked and disabled
+/// when columns are missing, leaving the saved setting alone for the next database.
+pub fn feature_checkbox(ui: &mut Ui, schema: &Schema, feature: Feature, checked: &mut bool, label: &str) {
+    if let Err(e) = schema.require(feature) {
+        ui.add_enabled(false, egui::Checkbox::new(&mut false, label))
+            .on_disabled_hover_text(e.to_string());
+    } else {
+        ui.checkbox(checked, label);
+    }
+}
+
+pub fn multi_combo_box(ui: &mut Ui, label: &str, selected: &mut Vec<String>, list: &Vec<String>) {
+    let text = if selected.is_empty() { "None".to_string() } else { selected.join(", ") };
+    egui::ComboBox::from_id_source(label)
+        .selected_text(text)
+        .show_ui(ui, |ui| {
+            for item in list {
+                let mut checked = selected.contains(item);
+                if ui.checkbox(&mut checked, item).changed() {
+                    if checked {
+                        selected.push(item.clone());
+                    } else {
+                        selected.retain(|s| s != item);
+                    }
+                }
+            }
+        });
+}
+
+/// Matched rows of a Find & Replace with a checkbox to leave each one out, only the visible rows are laid out.
+pub fn replace_preview_table(ui: &mut Ui, rows: &mut [ReplaceRow]) {
+    let widths = column_widths(ui, &[Some(24.0), Some(60.0), None, Some(100.0), None, None]);
+    // The header stays above the scroll area, fixed widths keep it lined up with the rows
+    ui.horizontal(|ui| {
+        for (heading, width) in ["", "rowid", "filename", "column", "current value", "new value"].into_iter().zip(&widths) {
+            cell(ui, *width, |ui| ui.strong(heading));
+        }
+    });
+    let row_height = ui.spacing().interact_size.y;
+    egui::ScrollArea::vertical()
+        .max_height(300.0)
+        .auto_shrink([false, true])
+        .show_rows(ui, row_height, rows.len(), |ui, range| {
+            for row in &mut rows[range] {
+                ui.horizontal(|ui| {
+                    cell(ui, widths[0], |ui| ui.checkbox(&mut row.include, ""));
+                    text_cell(ui, widths[1], row.id.to_string());
+                    text_cell(ui, widths[2], &row.filename);
+                    text_cell(ui, widths[3], &row.column);
+                    text_cell(ui, widths[4], &row.old);
+                    text_cell(ui, widths[5], &row.new);
+                });
+            }
+        });
+}
+
+/// Widths for the columns of a table, `Some` columns are fixed and the rest share what is left of the panel.
+fn column_widths(ui: &Ui, columns: &[Option<f32>]) -> Vec<f32> {
+    let spacing = ui.spacing().item_spacing.x * columns.len() as f32;
+    let fixed: f32 = columns.iter().flatten().sum();
+    let flexible = columns.iter().filter(|column| column.is_none()).count().max(1) as f32;
+    let share = ((ui.available_width() - fixed - spacing) / flexible).max(60.0);
+    columns.iter().map(|column| column.unwrap_or(share)).collect()
+}
+
+/// One table cell of a fixed width, so rows line up with a header drawn outside their scroll area.
+fn cell<R>(ui: &mut Ui, width: f32, add: impl FnOnce(&mut Ui) -> R) -> R {
+    let size = egui::vec2(width, ui.spacing().interact_size.y);
+    ui.allocate_ui_with_layout(size, egui::Layout::left_to_right(egui::Align::Center), |ui| {
+        ui.set_width(width);
+        add(ui)
+    }).inner
+}
+
+/// Text cut to the cell's width, hovering shows all of it.
+fn text_cell(ui: &mut Ui, width: f32, text: impl Into<egui::WidgetText>) {
+    cell(ui, width, |ui| ui.add(egui::Label::new(text).truncate()));
+}
+
+/// Duplicate groups matching the view's filter in its sort order, as (group, row) pairs with the keeper first.
+/// `ResultsView::rows` keeps the result until the filter, the sort or the groups change.
+pub fn visible_results(groups: &[DuplicateGroup], view: &ResultsView) -> Vec<(usize, usize)> {
+    let filter = view.filter.to_lowercase();
+    let matches = |row: &ResultRow| {
+        [&row.filename, &row.pathname, &row.description].iter().any(|text| text.to_lowercase().contains(&filter))
+    };
+    let mut visible: Vec<usize> = (0..groups.len())
+        .filter(|&index| filter.is_empty() || groups[index].rows.iter().any(matches))
+        .collect();
+    visible.sort_by(|&a, &b| {
+        let (a, b) = (&groups[a].rows, &groups[b].rows);
+        match view.sort {
+            ResultSort::Filename => a[0].filename.cmp(&b[0].filename),
+            ResultSort::Pathname => a[0].pathname.cmp(&b[0].pathname),
+            ResultSort::Duration => duration_seconds(&a[0].duration).total_cmp(&duration_seconds(&b[0].duration)),
+            ResultSort::Records => a.len().cmp(&b.len()),
+        }
+    });
+    if view.descending {
+        visible.reverse();
+    }
+    visible.into_iter()
+        .flat_map(|group| (0..groups[group].rows.len()).map(move |row| (group, row)))
+        .collect()
+}
+
+/// Seconds in a `[[HH:]MM:]SS[.fff]` duration, durations that aren't one sort after all the others.
+fn duration_seconds(duration: &str) -> f64 {
+    duration.trim().split(':')
+        .try_fold(0.0, |total, part| part.trim().parse::<f64>().ok().filter(|part| part.is_finite()).map(|part| total * 60.0 + part))
+        .unwrap_or(f64::INFINITY)
+}
+
+/// Every marked record under its keeper, with a checkbox to flip keep or remove. Only the visible rows are laid out.
+pub fn results_table(ui: &mut Ui, groups: &mut [DuplicateGroup], visible: &[(usize, usize)]) {
+    let widths = column_widths(ui, &[Some(24.0), Some(160.0), Some(60.0), None, None, Some(90.0), Some(60.0), Some(80.0), None]);
+    ui.horizontal(|ui| {
+        let headings = ["remove", "why", "rowid", "filename", "pathname", "duration", "channels", "sample rate", "Description"];
+        for (heading, width) in headings.into_iter().zip(&widths) {
+            cell(ui, *width, |ui| ui.strong(heading));
+        }
+    });
+    let row_height = ui.spacing().interact_size.y;
+    egui::ScrollArea::vertical()
+        .id_source("duplicate_results")
+        .max_height(400.0)
+        .auto_shrink([false, true])
+        .show_rows(ui, row_height, visible.len(), |ui, range| {
+            for &(group, row) in &visible[range] {
+                let row = &mut groups[group].rows[row];
+                ui.horizontal(|ui| {
+                    cell(ui, widths[0], |ui| ui.checkbox(&mut row.remove, ""));
+                    if row.keeper {
+                        cell(ui, widths[1], |ui| ui.strong("Keeper"));
+                    } else {
+                        text_cell(ui, widths[1], &row.reason);
+                    }
+                    text_cell(ui, widths[2], row.id.to_string());
+                    text_cell(ui, widths[3], &row.filename);
+                    text_cell(ui, widths[4], &row.pathname);
+                    text_cell(ui, widths[5], &row.duration);
+                    text_cell(ui, widths[6], &row.channels);
+                    text_cell(ui, widths[7], &row.sample_rate);
+                    text_cell(ui, widths[8], &row.description);
+                });
+            }
+        });
+}
+
+pub fn batch_table(ui: &mut Ui, rules: &[BatchRule]) {
+    egui::ScrollArea::both()
+        .id_source("batch_rules")
+        .max_height(200.0)
+        .auto_shrink([false, true])
+        .show(ui, |ui| {
+            egui::Grid::new("batch_rules").striped(true).num_columns(6).show(ui, |ui| {
+                for heading in ["line", "column", "find", "replace", "regex", "matches"] {
+                    ui.strong(heading);
+                }
+                ui.end_row();
+                for rule in rules {
+                    ui.label(rule.line.to_string());
+                    ui.label(&rule.column);
+                    ui.label(&rule.find);
+                    ui.label(&rule.replace);
+                    ui.label(if rule.regex { "yes" } else { "" });
+                    ui.label(rule.count.map(|count| count.to_string()).unwrap_or_default());
+                    ui.end_row();
+                }
+            });
+        });
+}
+
 pub fn order_help(ui: &mut Ui) {
     ui.heading("Column in order of Priority and whether it should be DESCending or ASCending.");
     ui.label("These are SQL arguments and Google/ChatGPT can help you figure out how to compose them");
+    ui.label("The Rule row below the list builds these for you from a column, a comparison and a value. Hover a rule to see its SQL");
     ui.horizontal(|_|{});
     ui.heading("Examples:");
     ui.heading("CASE WHEN pathname LIKE '%Audio Files%' THEN 1 ELSE 0 END ASC");
@@ -78,4 +289,20 @@ pub fn order_help(ui: &mut Ui) {
                     //                 }
                     //             });
                     //         }
-                    //     });
\ No newline at end of file
+                    //     });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_sort_by_length() {
+        assert_eq!(duration_seconds("00:01:05.500"), 65.5);
+        assert_eq!(duration_seconds("1:00:00"), 3600.0);
+        assert_eq!(duration_seconds("9.25"), 9.25);
+        assert_eq!(duration_seconds(""), f64::INFINITY);
+        assert_eq!(duration_seconds("unknown"), f64::INFINITY);
+        assert!(duration_seconds("0:59") < duration_seconds("10:00"));
+        assert!(duration_seconds("2:00") < duration_seconds("10:00"));
+    }
+}
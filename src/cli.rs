@@ -0,0 +1,419 @@
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::engine::{DedupeEngine, SearchOptions};
+use crate::processing::*;
+use crate::schema::{get_schema, Feature};
+use crate::report::{Message, Reporter};
+
+/// Exit codes of a headless run.
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+
+const HELP: &str = "
+Usage: SMDB_Companion <command> <database> [options]
+       SMDB_Companion                            Opens the window
+
+Commands:
+    dupes <database>                  Search for duplicate filenames
+    tags <database>                   Search for filenames containing audiosuite tags
+    compare <database> <other>        Search for records that are also in another database
+    replace <database>                Find and replace text in one or more columns
+
+Dupes options:
+        --group <column>              Search for duplicates within each value of a column, empty values skipped
+        --group-null                  Used with --group, records with an empty value are searched together
+    -D, --deep-dive                   Also search for filenames ending in .# or .M before the extension
+    -n, --no-filename-check           Skip the basic duplicate filename search, use with --deep-dive
+        --order <file>                Keeper priority rules, one SQL ORDER BY line per line of the file
+
+Tags options:
+        --tags <file>                 Tags to search for, one per line
+
+Compare options:
+        --match <column>              Also require this column to match, can be given more than once
+
+Removal options, for dupes, tags and compare:
+    -r, --remove                      Remove the records that were found, otherwise only report them
+    -u, --unsafe                      Remove from the database itself instead of a _thinned copy
+    -d, --create-duplicates-database  Also write the removed records to a _dupes database
+
+Replace options:
+    -c, --column <column>             Column to search, can be given more than once
+    -f, --find <text>                 Text to find
+    -w, --with <text>                 Replacement text
+        --batch <file>                Apply the column,find,replace[,regex] rules of a CSV file in order
+        --regex                       Treat the find text as a regular expression, $1 inserts a capture group
+    -i, --ignore-case                 Match regardless of case
+        --whole-word                  Only match whole words
+        --dirty                       Mark changed records as dirty, the default when the database has _Dirty
+        --no-dirty                    Leave the dirty flag of changed records alone
+        --dry-run                     Only count the matches
+
+General options:
+        --tjf                         Use the TJF order and tag defaults
+    -v, --verbose                     Print progress while working
+    -h, --help                        Display this help message
+
+Exit codes:
+    0  Success
+    1  The database could not be processed
+    2  Invalid arguments
+
+Examples:
+    SMDB_Companion dupes library.sqlite --group Show --deep-dive
+    SMDB_Companion dupes library.sqlite -Dr --unsafe
+    SMDB_Companion compare library.sqlite archive.sqlite --match duration --remove
+    SMDB_Companion replace library.sqlite -c Library -c Description -f Sounddogs -w SoundDogs --no-dirty
+    SMDB_Companion replace library.sqlite --batch cleanup.csv --dry-run
+";
+
+#[derive(PartialEq)]
+enum Command { Dupes, Tags, Compare, Replace }
+
+struct Options {
+    command: Command,
+    database: String,
+    compare_db: Option<String>,
+    group: Option<String>,
+    group_null: bool,
+    deep_dive: bool,
+    filename_check: bool,
+    order: Option<String>,
+    tags: Option<String>,
+    match_columns: Vec<String>,
+    remove: bool,
+    safe: bool,
+    dupes_db: bool,
+    columns: Vec<String>,
+    find: Option<String>,
+    replace: Option<String>,
+    batch: Option<String>,
+    regex: bool,
+    match_case: bool,
+    whole_word: bool,
+    /// `None` marks records as dirty when the database can, like the window does.
+    dirty: Option<bool>,
+    dry_run: bool,
+    tjf: bool,
+    verbose: bool,
+}
+
+/// Runs the command line in `args`, including the program name, and returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse_args(args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", HELP);
+            return EXIT_OK;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Run with --help for usage.");
+            return EXIT_USAGE;
+        }
+    };
+
+    let result = match options.command {
+        Command::Dupes | Command::Tags | Command::Compare => run_search(&options),
+        Command::Replace => run_replace(&options),
+    };
+    match result {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            EXIT_FAILED
+        }
+    }
+}
+
+/// `Ok(None)` when help was asked for.
+fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        command: Command::Dupes,
+        database: String::new(),
+        compare_db: None,
+        group: None,
+        group_null: false,
+        deep_dive: false,
+        filename_check: true,
+        order: None,
+        tags: None,
+        match_columns: Vec::new(),
+        remove: false,
+        safe: true,
+        dupes_db: false,
+        columns: Vec::new(),
+        find: None,
+        replace: None,
+        batch: None,
+        regex: false,
+        match_case: true,
+        whole_word: false,
+        dirty: None,
+        dry_run: false,
+        tjf: false,
+        verbose: false,
+    };
+    let mut positional = Vec::new();
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        // Options that take a value read it from the next argument
+        let mut value = |name: &str| -> Result<String, String> {
+            i += 1;
+            args.get(i).cloned().ok_or_else(|| format!("{} needs a value", name))
+        };
+        match arg {
+            "--help" => return Ok(None),
+            "--group" => options.group = Some(value(arg)?),
+            "--group-null" => options.group_null = true,
+            "--deep-dive" => options.deep_dive = true,
+            "--no-filename-check" => options.filename_check = false,
+            "--order" => options.order = Some(value(arg)?),
+            "--tags" => options.tags = Some(value(arg)?),
+            "--match" => options.match_columns.push(value(arg)?),
+            "--remove" => options.remove = true,
+            "--unsafe" => options.safe = false,
+            "--create-duplicates-database" => options.dupes_db = true,
+            "--column" | "-c" => options.columns.push(value(arg)?),
+            "--find" | "-f" => options.find = Some(value(arg)?),
+            "--with" | "-w" => options.replace = Some(value(arg)?),
+            "--batch" => options.batch = Some(value(arg)?),
+            "--regex" => options.regex = true,
+            "--ignore-case" => options.match_case = false,
+            "--whole-word" => options.whole_word = true,
+            "--dirty" => options.dirty = Some(true),
+            "--no-dirty" => options.dirty = Some(false),
+            "--dry-run" => options.dry_run = true,
+            "--tjf" => options.tjf = true,
+            "--verbose" => options.verbose = true,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                for c in arg[1..].chars() {
+                    match c {
+                        'h' => return Ok(None),
+                        'D' => options.deep_dive = true,
+                        'n' => options.filename_check = false,
+                        'r' => options.remove = true,
+                        'u' => options.safe = false,
+                        'd' => options.dupes_db = true,
+                        'i' => options.match_case = false,
+                        'v' => options.verbose = true,
+                        _ => return Err(format!("Unknown option: -{}", c)),
+                    }
+                }
+            }
+            _ => positional.push(args[i].clone()),
+        }
+        i += 1;
+    }
+
+    let mut positional = positional.into_iter();
+    options.command = match positional.next().as_deref() {
+        Some("dupes") => Command::Dupes,
+        Some("tags") => Command::Tags,
+        Some("compare") => Command::Compare,
+        Some("replace") => Command::Replace,
+        Some(other) => return Err(format!("Unknown command: {}", other)),
+        None => return Err("No command given".to_string()),
+    };
+    options.database = positional.next().ok_or("No database given")?;
+    if options.command == Command::Compare {
+        options.compare_db = Some(positional.next().ok_or("No database given to compare against")?);
+    }
+    if let Some(extra) = positional.next() {
+        return Err(format!("Unexpected argument: {}", extra));
+    }
+
+    if options.command == Command::Replace && options.batch.is_none() {
+        if options.find.as_deref().unwrap_or_default().is_empty() {
+            return Err("replace needs --find text or a --batch file".to_string());
+        }
+        if options.replace.is_none() {
+            return Err("replace needs --with text, use --with \"\" to delete the found text".to_string());
+        }
+        if options.columns.is_empty() {
+            return Err("replace needs at least one --column".to_string());
+        }
+    }
+    Ok(Some(options))
+}
+
+/// Sends status messages to stderr while `task` runs, when verbose.
+fn with_reporter<T>(verbose: bool, task: impl FnOnce(&Reporter) -> T) -> T {
+    let (sender, receiver) = mpsc::channel();
+    let printer = thread::spawn(move || {
+        for message in receiver {
+            if let Message::Status(_, status) = message {
+                if verbose && !status.is_empty() {
+                    eprintln!("{}", status);
+                }
+            }
+        }
+    });
+    let reporter = Reporter::new(sender);
+    let result = task(&reporter);
+    drop(reporter);
+    let _ = printer.join();
+    result
+}
+
+/// `--dirty` or `--no-dirty` when given, otherwise whether the database has a `_Dirty` column to set.
+fn mark_dirty(options: &Options) -> Result<bool, String> {
+    match options.dirty {
+        Some(dirty) => Ok(dirty),
+        None => get_schema(&options.database)
+            .map(|schema| schema.supports(Feature::Dirty))
+            .map_err(|e| format!("Failed to read {}: {}", options.database, e)),
+    }
+}
+
+/// Opening a missing path would create an empty database, so check for it first.
+fn check_database(path: &str) -> Result<(), String> {
+    if Path::new(path).is_file() {
+        Ok(())
+    } else {
+        Err(format!("{} does not exist", path))
+    }
+}
+
+/// Non empty lines of a list file, lines starting with # are comments.
+fn read_list(path: &str) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    Ok(text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn run_search(options: &Options) -> Result<(), String> {
+    check_database(&options.database)?;
+    let order = match &options.order {
+        Some(path) => read_list(path)?,
+        None if options.tjf => tjf_order(),
+        None => default_order(),
+    };
+    let tags = match &options.tags {
+        Some(path) => read_list(path)?,
+        None if options.tjf => tjf_tags(),
+        None => default_tags(),
+    };
+    if let Some(compare_db) = &options.compare_db {
+        check_database(compare_db)?;
+    }
+
+    let search = match options.command {
+        Command::Dupes => SearchOptions {
+            order,
+            filename_check: options.filename_check,
+            group_by: options.group.clone(),
+            group_null: options.group_null,
+            deep_dive: options.deep_dive,
+            ..SearchOptions::default()
+        },
+        Command::Tags => SearchOptions { tags: Some(tags), ..SearchOptions::default() },
+        Command::Compare => SearchOptions {
+            compare_db: options.compare_db.clone(),
+            compare_match: options.match_columns.clone(),
+            ..SearchOptions::default()
+        },
+        Command::Replace => unreachable!("replace is handled by run_replace"),
+    };
+    let engine = DedupeEngine::new(options.database.clone(), search);
+
+    with_reporter(options.verbose, |reporter| {
+        let size = get_db_size(&options.database).map_err(|e| format!("Failed to open {}: {}", options.database, e))?;
+        println!("{}: {} records", options.database, size);
+        let report = engine.search(reporter).map_err(|e| format!("Search failed: {}", e))?;
+
+        let search = engine.options();
+        if search.filename_check {
+            match &search.group_by {
+                Some(column) => println!("Found {} duplicate filename records grouped by {}", report.duplicates.len(), column),
+                None => println!("Found {} duplicate filename records", report.duplicates.len()),
+            }
+        }
+        if search.deep_dive {
+            println!("Found {} records ending in .# or .M", report.deep_dive.len());
+        }
+        if search.tags.is_some() {
+            println!("Found {} records with matching tags", report.tags.len());
+        }
+        if let Some(compare_db) = &search.compare_db {
+            println!("Found {} records in {}", report.compare.len(), compare_db);
+        }
+
+        let records = report.marked();
+        println!("Marked {} total records for removal.", records.len());
+        if options.remove && !records.is_empty() {
+            let summary = engine.remove(&records, options.safe, options.dupes_db, reporter)
+                .map_err(|e| format!("Failed to remove records: {}", e))?;
+            println!("{}", summary);
+        }
+        Ok(())
+    })
+}
+
+fn run_replace(options: &Options) -> Result<(), String> {
+    check_database(&options.database)?;
+    match &options.batch {
+        Some(path) => run_batch(options, path),
+        None => run_single_replace(options),
+    }
+}
+
+fn run_single_replace(options: &Options) -> Result<(), String> {
+    let find = options.find.clone().unwrap_or_default();
+    let replace = options.replace.clone().unwrap_or_default();
+    let matcher = Matcher::new(&find, options.regex, options.match_case, options.whole_word)
+        .map_err(|e| format!("Invalid regular expression: {}", e))?;
+
+    with_reporter(options.verbose, |reporter| {
+        let rows = smreplace_get(&options.database, &matcher, &replace, &options.columns, reporter)
+            .map_err(|e| format!("Search failed: {}", e))?;
+        for (column, count) in replace_counts(&rows) {
+            println!("{}: {} matches", column, count);
+        }
+        println!("Found {} matches for '{}'", rows.len(), find);
+        if options.dry_run || rows.is_empty() {
+            return Ok(());
+        }
+        let count = smreplace_process(&options.database, &rows, mark_dirty(options)?, reporter)
+            .map_err(|e| format!("Replace failed: {}", e))?;
+        println!("{} values replaced", count);
+        Ok(())
+    })
+}
+
+fn run_batch(options: &Options, path: &str) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let rules = parse_batch_csv(&text).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let rules = rules.into_iter()
+        .map(|rule| {
+            Matcher::new(&rule.find, rule.regex, options.match_case, options.whole_word)
+                .map(|matcher| (rule.clone(), matcher))
+                .map_err(|e| format!("Line {}: invalid regular expression: {}", rule.line, e))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    with_reporter(options.verbose, |reporter| {
+        let counts = smreplace_batch_counts(&options.database, &rules, reporter)
+            .map_err(|e| format!("Count failed: {}", e))?;
+        for ((rule, _), count) in rules.iter().zip(&counts) {
+            println!("Line {}: {} '{}' -> '{}': {} matches", rule.line, rule.column, rule.find, rule.replace, count);
+        }
+        if options.dry_run {
+            return Ok(());
+        }
+        let count = smreplace_batch_process(&options.database, &rules, mark_dirty(options)?, reporter)
+            .map_err(|e| format!("Batch failed: {}", e))?;
+        println!("Applied {} rules, {} values replaced", rules.len(), count);
+        Ok(())
+    })
+}
@@ -0,0 +1,317 @@
+use std::collections::HashSet;
+
+use crate::processing::*;
+
+pub use crate::error::DbError;
+pub use crate::processing::{DuplicateGroup, FileRecord, KeeperChange, OrderSimulation, ResultRow};
+pub use crate::schema::{Feature, Schema};
+pub use crate::report::{Message, Reporter, Stage};
+
+/// Which searches to run on a database and how to pick the copy to keep, independent of any UI state.
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    /// Keeper priority, one ORDER BY term per entry. The first record of each group is kept.
+    pub order: Vec<String>,
+    pub filename_check: bool,
+    /// Search for duplicates within each value of this column instead of across the whole database.
+    pub group_by: Option<String>,
+    /// With `group_by`, records without a value are searched as one group instead of skipped.
+    pub group_null: bool,
+    pub deep_dive: bool,
+    /// Filename fragments of processed copies, `None` skips the tag search.
+    pub tags: Option<Vec<String>>,
+    /// Marks records that are also in this database.
+    pub compare_db: Option<String>,
+    /// Columns that must match as well as the filename for a record to count as in `compare_db`.
+    pub compare_match: Vec<String>,
+}
+
+/// The records each search marked for removal. A record can be marked by more than one search.
+#[derive(Clone, Debug, Default)]
+pub struct DedupeReport {
+    pub duplicates: HashSet<FileRecord>,
+    pub deep_dive: HashSet<FileRecord>,
+    pub tags: HashSet<FileRecord>,
+    pub compare: HashSet<FileRecord>,
+}
+
+impl DedupeReport {
+    /// Every record marked by any search.
+    pub fn marked(&self) -> HashSet<FileRecord> {
+        self.duplicates.iter()
+            .chain(&self.deep_dive)
+            .chain(&self.tags)
+            .chain(&self.compare)
+            .cloned()
+            .collect()
+    }
+}
+
+pub struct DedupeEngine {
+    db_path: String,
+    options: SearchOptions,
+}
+
+impl DedupeEngine {
+    pub fn new(db_path: impl Into<String>, options: SearchOptions) -> Self {
+        Self { db_path: db_path.into(), options }
+    }
+
+    pub fn db_path(&self) -> &str {
+        &self.db_path
+    }
+
+    pub fn options(&self) -> &SearchOptions {
+        &self.options
+    }
+
+    /// Runs every enabled search on one connection, streaming each search's status and records to `reporter`
+    /// under its own `Stage`. A failed or cancelled search stops the ones after it and is returned as the error.
+    pub fn search(&self, reporter: &Reporter) -> Result<DedupeReport, DbError> {
+        reporter.working(Stage::Main, true);
+        let result = self.run_searches(reporter);
+        match &result {
+            Ok(report) => {
+                let marked = report.marked();
+                if marked.is_empty() {
+                    reporter.status(Stage::Main, "No records marked for removal.");
+                } else {
+                    reporter.status(Stage::Main, format!("Marked {} total records for removal.", marked.len()));
+                }
+                reporter.records(Stage::Main, marked);
+            }
+            Err(DbError::Cancelled) => {
+                reporter.status(Stage::Main, "Search cancelled. No records marked for removal.");
+                reporter.records(Stage::Main, HashSet::new());
+            }
+            Err(e) => reporter.status(Stage::Main, format!("Search failed: {}", e)),
+        }
+        reporter.working(Stage::Main, false);
+        result
+    }
+
+    fn run_searches(&self, reporter: &Reporter) -> Result<DedupeReport, DbError> {
+        let options = &self.options;
+        let mut report = DedupeReport::default();
+
+        reporter.status(Stage::Main, format!("Opening {}", display_name(&self.db_path)));
+        let mut conn = open_soundminer(&self.db_path)?;
+        reporter.watch(&conn);
+        let schema = Schema::read(&conn)?;
+        if options.filename_check || options.deep_dive {
+            require_order(&conn, &options.order)?;
+        }
+
+        if options.filename_check {
+            schema.require(Feature::Duplicates)?;
+            reporter.status(Stage::Main, "Searching for duplicate filenames");
+            report.duplicates = run_stage(reporter, Stage::Group, "Duplicate filename search",
+                || gather_duplicate_filenames_in_database(&mut conn, &options.order, options.group_by.as_deref(), options.group_null, reporter),
+                |found| match &options.group_by {
+                    Some(column) => format!("Found {} duplicate filename records grouped by {}", found, column),
+                    None => format!("Found {} duplicate filename records", found),
+                },
+            )?;
+        }
+        if options.deep_dive {
+            schema.require(Feature::DeepDive)?;
+            reporter.status(Stage::Main, "Performing Deep Dive Search");
+            report.deep_dive = run_stage(reporter, Stage::Deep, "Deep Dive search",
+                || gather_records_with_trailing_numbers(&mut conn, &options.order, reporter),
+                |found| format!("Found {} records ending in .# or .M", found),
+            )?;
+        }
+        if let Some(tags) = &options.tags {
+            schema.require(Feature::Tags)?;
+            reporter.status(Stage::Main, "Searching for tags");
+            report.tags = run_stage(reporter, Stage::Tags, "Tag search",
+                || gather_filenames_with_tags(&mut conn, tags, reporter),
+                |found| format!("Found {} records with matching tags", found),
+            )?;
+        }
+        if let Some(compare_db) = &options.compare_db {
+            schema.require(Feature::Compare)?;
+            reporter.status(Stage::Main, "Comparing against database");
+            report.compare = run_stage(reporter, Stage::Compare, "Database comparison",
+                || gather_compare_database_overlaps(&conn, compare_db, &options.compare_match, reporter),
+                |found| format!("Found {} records in {}", found, display_name(compare_db)),
+            )?;
+        }
+        reporter.check_cancelled()?;
+        Ok(report)
+    }
+
+    /// Groups the records a search marked under the keepers they lost to, each with the reason it was marked,
+    /// for reviewing before they are removed.
+    pub fn groups(&self, report: &DedupeReport, reporter: &Reporter) -> Result<Vec<DuplicateGroup>, DbError> {
+        let mut groups = load_duplicate_groups(&self.db_path, &report.marked(), &self.options.order, reporter)?;
+        // Records without a keeper were marked by a search that doesn't rank
+        for row in groups.iter_mut().flat_map(|group| &mut group.rows).filter(|row| !row.keeper && row.reason.is_empty()) {
+            let record = row.record();
+            if report.tags.contains(&record) {
+                row.reason = "Filename contains an AudioSuite tag".to_string();
+            } else if let Some(compare_db) = self.options.compare_db.as_deref().filter(|_| report.compare.contains(&record)) {
+                row.reason = format!("Also in {}", display_name(compare_db));
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Compares the keepers the duplicate filename search picks with `saved` against those it picks with
+    /// the options' order, without marking or removing anything.
+    pub fn simulate(&self, saved: &[String], reporter: &Reporter) -> Result<OrderSimulation, DbError> {
+        reporter.working(Stage::Order, true);
+        let result = self.run_simulation(saved, reporter);
+        match &result {
+            Ok(simulation) if simulation.changed == 0 => {
+                reporter.status(Stage::Order, format!("All {} duplicate groups keep the same record.", simulation.groups));
+            }
+            Ok(simulation) => reporter.status(Stage::Order, format!(
+                "{} of {} duplicate groups keep a different record with the current order.", simulation.changed, simulation.groups
+            )),
+            Err(DbError::Cancelled) => reporter.status(Stage::Order, "Simulation cancelled."),
+            Err(e) => reporter.status(Stage::Order, format!("Simulation failed: {}", e)),
+        }
+        reporter.working(Stage::Order, false);
+        result
+    }
+
+    fn run_simulation(&self, saved: &[String], reporter: &Reporter) -> Result<OrderSimulation, DbError> {
+        reporter.status(Stage::Order, format!("Opening {}", display_name(&self.db_path)));
+        let mut conn = open_soundminer(&self.db_path)?;
+        reporter.watch(&conn);
+        Schema::read(&conn)?.require(Feature::Duplicates)?;
+        let options = &self.options;
+        require_order(&conn, saved)?;
+        require_order(&conn, &options.order)?;
+        Ok(simulate_keeper_order(&mut conn, saved, &options.order, options.group_by.as_deref(), options.group_null, reporter)?)
+    }
+
+    /// Removes `records`, from a verified `_thinned` copy when `safe`, see `remove_duplicates`.
+    pub fn remove(&self, records: &HashSet<FileRecord>, safe: bool, dupes_db: bool, reporter: &Reporter) -> Result<String, DbError> {
+        remove_duplicates(&self.db_path, records, safe, dupes_db, reporter)
+    }
+}
+
+fn run_stage<E>(
+    reporter: &Reporter,
+    stage: Stage,
+    search: &str,
+    gather: impl FnOnce() -> Result<HashSet<FileRecord>, E>,
+    summary: impl FnOnce(usize) -> String,
+) -> Result<HashSet<FileRecord>, DbError>
+where
+    DbError: From<E>,
+{
+    reporter.check_cancelled()?;
+    reporter.working(stage, true);
+    let result = gather().map_err(DbError::from);
+    match &result {
+        Ok(records) => {
+            reporter.status(stage, summary(records.len()));
+            reporter.records(stage, records.clone());
+        }
+        Err(DbError::Cancelled) => reporter.status(stage, format!("{} cancelled", search)),
+        Err(e) => reporter.status(stage, format!("{} failed: {}", search, e)),
+    }
+    reporter.working(stage, false);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::tests::{library, reporter};
+
+    fn engine(name: &str, options: SearchOptions) -> DedupeEngine {
+        let path = library(name, &[
+            ("door.wav", "/b/door.wav", "", ""),
+            ("door.wav", "/a/door.wav", "", ""),
+            ("door.1.wav", "/c/door.1.wav", "", ""),
+            ("gate-AVSEQ_.wav", "/a/gate-AVSEQ_.wav", "", ""),
+            ("bell.wav", "/a/bell.wav", "", ""),
+        ]);
+        DedupeEngine::new(path, SearchOptions { order: vec!["pathname ASC".to_string()], ..options })
+    }
+
+    fn ids(records: &HashSet<FileRecord>) -> Vec<usize> {
+        let mut ids: Vec<_> = records.iter().map(|record| record.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn search_reports_each_search_separately() {
+        let engine = engine("engine_search", SearchOptions {
+            filename_check: true,
+            deep_dive: true,
+            tags: Some(vec!["-AVSEQ_".to_string()]),
+            ..Default::default()
+        });
+        let report = engine.search(&reporter()).unwrap();
+        assert_eq!(ids(&report.duplicates), vec![1]);
+        assert_eq!(ids(&report.deep_dive), vec![1, 3]);
+        assert_eq!(ids(&report.tags), vec![4]);
+        assert!(report.compare.is_empty());
+        assert_eq!(ids(&report.marked()), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn groups_explain_why_each_record_was_marked() {
+        let engine = engine("engine_groups", SearchOptions {
+            filename_check: true,
+            tags: Some(vec!["-AVSEQ_".to_string()]),
+            ..Default::default()
+        });
+        let report = engine.search(&reporter()).unwrap();
+        let groups = engine.groups(&report, &reporter()).unwrap();
+        let door = groups.iter().find(|group| group.rows[0].filename == "door.wav").unwrap();
+        assert_eq!(door.rows.iter().map(|row| (row.id, row.keeper)).collect::<Vec<_>>(), vec![(2, true), (1, false)]);
+        let gate = groups.iter().find(|group| group.rows[0].filename == "gate-AVSEQ_.wav").unwrap();
+        assert_eq!(gate.rows[0].reason, "Filename contains an AudioSuite tag");
+    }
+
+    #[test]
+    fn remove_takes_the_marked_records_out() {
+        let engine = engine("engine_remove", SearchOptions { filename_check: true, ..Default::default() });
+        let report = engine.search(&reporter()).unwrap();
+        engine.remove(&report.marked(), false, false, &reporter()).unwrap();
+        let after = engine.search(&reporter()).unwrap();
+        assert!(after.marked().is_empty());
+        assert_eq!(crate::processing::get_db_size(engine.db_path()).unwrap(), 4);
+    }
+
+    #[test]
+    fn search_refuses_databases_missing_its_columns() {
+        let engine = engine("engine_missing", SearchOptions { compare_db: Some("other.sqlite".to_string()), ..Default::default() });
+        let conn = open_soundminer(engine.db_path()).unwrap();
+        conn.execute("ALTER TABLE justinmetadata DROP COLUMN duration", []).unwrap();
+        drop(conn);
+        assert!(matches!(engine.search(&reporter()), Err(DbError::MissingColumns(..))));
+    }
+
+    #[test]
+    fn search_needs_only_the_columns_of_its_order() {
+        let engine = engine("engine_order_columns", SearchOptions { filename_check: true, ..Default::default() });
+        let conn = open_soundminer(engine.db_path()).unwrap();
+        conn.execute("ALTER TABLE justinmetadata DROP COLUMN pathname", []).unwrap();
+        assert!(Schema::read(&conn).unwrap().supports(Feature::Duplicates));
+        drop(conn);
+
+        match engine.search(&reporter()) {
+            Err(DbError::InvalidOrder(line, message)) => {
+                assert_eq!(line, "pathname ASC");
+                assert!(message.contains("pathname"), "{}", message);
+            }
+            other => panic!("expected an invalid order, got {:?}", other.map(|report| report.marked())),
+        }
+        let by_rowid = DedupeEngine::new(engine.db_path(), SearchOptions { filename_check: true, order: vec!["rowid DESC".to_string()], ..Default::default() });
+        assert_eq!(ids(&by_rowid.search(&reporter()).unwrap().duplicates), vec![1]);
+
+        // Without pathnames the changed keepers are listed by rowid alone
+        let simulation = by_rowid.simulate(&["rowid ASC".to_string()], &reporter()).unwrap();
+        assert_eq!(simulation.changed, 1);
+        assert_eq!(simulation.sample[0].before, (1, String::new()));
+        assert_eq!(simulation.sample[0].after, (2, String::new()));
+    }
+}
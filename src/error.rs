@@ -0,0 +1,76 @@
+use std::fmt;
+use std::io;
+
+use rusqlite::ErrorCode;
+use crate::schema::Feature;
+
+/// Errors from the processing layer, split by what the user can do about them.
+#[derive(Debug)]
+pub enum DbError {
+    /// The user cancelled the job, nothing was changed.
+    Cancelled,
+    /// Another program, usually Soundminer, is writing to the database.
+    Locked,
+    /// The file is not a SQLite database or is damaged.
+    Corrupt,
+    /// The file is a SQLite database without Soundminer's `justinmetadata` table.
+    NotSoundminer,
+    /// The database lacks columns a feature needs, usually because it comes from another Soundminer version.
+    MissingColumns(Feature, Vec<&'static str>),
+    /// The `_thinned` copy made before removing records did not match the source.
+    SafetyCheck(String),
+    /// A keeper order line SQLite can't sort this database by, with SQLite's reason.
+    InvalidOrder(String, String),
+    /// The database to compare against is the one being searched, which would mark every record.
+    CompareWithItself,
+    /// The database file is missing, or a file written next to it could not be replaced.
+    Io(io::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Cancelled => write!(f, "Cancelled by user"),
+            DbError::Locked => write!(f, "The database is locked by another program. Close it in Soundminer and try again"),
+            DbError::Corrupt => write!(f, "The file is not a SQLite database or is damaged"),
+            DbError::NotSoundminer => write!(f, "The database has no justinmetadata table, it is not a Soundminer database"),
+            DbError::MissingColumns(feature, columns) => write!(f, "{} needs columns missing from this database: {}", feature.name(), columns.join(", ")),
+            DbError::SafetyCheck(message) => write!(f, "Safety copy failed verification: {}", message),
+            DbError::InvalidOrder(line, message) => write!(f, "Keeper order line '{}' can't be used with this database: {}", line, message),
+            DbError::CompareWithItself => write!(f, "The comparison database is the database being searched, choose another one"),
+            DbError::Io(e) => write!(f, "{}", e),
+            DbError::Sqlite(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Io(e) => Some(e),
+            DbError::Sqlite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(error: rusqlite::Error) -> Self {
+        match &error {
+            rusqlite::Error::SqliteFailure(e, _) => match e.code {
+                ErrorCode::OperationInterrupted => DbError::Cancelled,
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => DbError::Locked,
+                ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt => DbError::Corrupt,
+                _ => DbError::Sqlite(error),
+            },
+            _ => DbError::Sqlite(error),
+        }
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(error: io::Error) -> Self {
+        DbError::Io(error)
+    }
+}
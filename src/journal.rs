@@ -0,0 +1,411 @@
+use std::path::Path;
+
+use rusqlite::{Connection, OptionalExtension, Result};
+use crate::error::DbError;
+use crate::processing::{open_soundminer, table_columns, PROGRESS_INTERVAL, TABLE};
+use crate::report::Reporter;
+
+/// Soundminer owns the schema of its databases, so the undo journal lives in a sibling `_undo` file
+/// that is attached as `journal` next to the database being changed. Removals made in a `_thinned`
+/// copy are journaled next to the source as well, since that is where Undo looks, with the copy as their target.
+pub fn undo_db_path(db_path: &str) -> String {
+    format!("{}_undo.sqlite", db_path.trim_end_matches(".sqlite"))
+}
+
+/// Attach before starting a transaction, SQLite refuses to attach inside one.
+pub fn attach(conn: &Connection, db_path: &str) -> Result<()> {
+    conn.execute("ATTACH DATABASE ?1 AS journal", [undo_db_path(db_path)])?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS journal.operations (
+            id INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            dirty INTEGER NOT NULL DEFAULT 0,
+            created TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE TABLE IF NOT EXISTS journal.replaced (
+            operation INTEGER NOT NULL,
+            row INTEGER NOT NULL,
+            column_name TEXT NOT NULL,
+            old_value,
+            old_dirty,
+            removed_by INTEGER
+        );"
+    )?;
+    // Journals written before operations could target another database
+    add_missing_column(conn, "operations", "target", "TEXT")?;
+    // or before entries followed their records into a removal
+    add_missing_column(conn, "replaced", "removed_by", "INTEGER")?;
+    Ok(())
+}
+
+fn add_missing_column(conn: &Connection, table: &str, column: &str, declaration: &str) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1, 'journal') WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE journal.{} ADD COLUMN {} {}", table, column, declaration), [])?;
+    }
+    Ok(())
+}
+
+pub fn detach(conn: &Connection) -> Result<()> {
+    conn.execute("DETACH DATABASE journal", [])?;
+    Ok(())
+}
+
+/// Starts a new undoable operation, call it inside the transaction that makes the change.
+/// `dirty` records that the change also set `_Dirty`, so undo puts the old flags back.
+/// `target` is the database changed when it isn't the one the journal belongs to.
+pub fn begin(conn: &Connection, description: &str, dirty: bool, target: Option<&str>) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO journal.operations (description, dirty, target) VALUES (?1, ?2, ?3)",
+        rusqlite::params![description, dirty, target],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Saves the current value of `column` for a row about to be updated from `expected`, which is the value as text.
+/// Returns false when the row no longer holds `expected`, the update will not touch it either.
+pub fn record_replaced(conn: &Connection, operation: i64, column: &str, id: i64, expected: &str, dirty: bool) -> Result<bool> {
+    let query = format!(
+        "INSERT INTO journal.replaced (operation, row, column_name, old_value, old_dirty)
+         SELECT ?1, rowid, ?2, \"{}\", {} FROM main.{} WHERE rowid = ?3 AND CAST(\"{}\" AS TEXT) = ?4",
+        column, if dirty { "_Dirty" } else { "NULL" }, TABLE, column
+    );
+    Ok(conn.prepare_cached(&query)?.execute(rusqlite::params![operation, column, id, expected])? > 0)
+}
+
+/// Copies whole rows about to be deleted, including their rowid, into a table of their own.
+/// Each removal gets its own table so a later change to the Soundminer schema can't break older entries.
+/// Earlier entries for the rows are marked as removed by `operation`, so together with the rowid they
+/// keep naming the row after VACUUM hands the rowid to another record, or a later removal takes it again.
+pub fn record_removed(conn: &Connection, operation: i64, ids: &[usize]) -> Result<()> {
+    conn.execute(&format!(
+        "CREATE TABLE IF NOT EXISTS journal.removed_{} AS SELECT rowid AS undo_rowid, * FROM main.{} WHERE 0",
+        operation, TABLE
+    ), [])?;
+    let placeholders = ids.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+    let query = format!("INSERT INTO journal.removed_{} SELECT rowid, * FROM main.{} WHERE rowid IN ({})", operation, TABLE, placeholders);
+    conn.execute(&query, rusqlite::params_from_iter(ids))?;
+    // Only entries of operations on the same database name these rows
+    conn.execute(&format!(
+        "UPDATE journal.replaced SET removed_by = ?1
+         WHERE removed_by IS NULL AND row IN (SELECT undo_rowid FROM journal.removed_{})
+         AND operation IN (SELECT id FROM journal.operations WHERE target IS (SELECT target FROM journal.operations WHERE id = ?1))",
+        operation
+    ), [operation])?;
+    Ok(())
+}
+
+/// For operations that turned out to change nothing.
+pub fn forget(conn: &Connection, operation: i64) -> Result<()> {
+    conn.execute("DELETE FROM journal.replaced WHERE operation = ?1", [operation])?;
+    conn.execute("DELETE FROM journal.operations WHERE id = ?1", [operation])?;
+    conn.execute(&format!("DROP TABLE IF EXISTS journal.removed_{}", operation), [])?;
+    Ok(())
+}
+
+fn forget_operations(conn: &Connection, target: Option<&str>) -> Result<()> {
+    let operations = target_operations(conn, target)?;
+    for operation in operations {
+        forget(conn, operation)?;
+    }
+    Ok(())
+}
+
+fn target_operations(conn: &Connection, target: Option<&str>) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare("SELECT id FROM journal.operations WHERE target IS ?1")?;
+    let operations = stmt.query_map([target], |row| row.get(0))?;
+    operations.collect()
+}
+
+/// Forgets the operations made in a copy of `db_path` that is about to be replaced by a fresh one.
+pub fn clear_target(db_path: &str, target: &str) -> Result<(), DbError> {
+    if !Path::new(&undo_db_path(db_path)).exists() {
+        return Ok(());
+    }
+    let conn = Connection::open_in_memory()?;
+    attach(&conn, db_path)?;
+    let result = forget_operations(&conn, Some(target));
+    detach(&conn)?;
+    Ok(result?)
+}
+
+/// Points the journal entries of `target`'s operations at the rowids VACUUM gave their records, as listed in
+/// `temp.vacuum_map (old_rowid, new_rowid)`. Entries for removed records keep their old rowid, they are
+/// found by the removal. Records deleted outside the app get a negative rowid no record can have.
+pub fn renumber(conn: &Connection, target: Option<&str>) -> Result<()> {
+    let operations = target_operations(conn, target)?;
+    let mut update = conn.prepare(
+        "UPDATE journal.replaced
+         SET row = COALESCE((SELECT new_rowid FROM temp.vacuum_map WHERE old_rowid = row), -ABS(row))
+         WHERE operation = ?1 AND removed_by IS NULL"
+    )?;
+    for &operation in &operations {
+        update.execute([operation])?;
+    }
+    Ok(())
+}
+
+/// When VACUUM's new rowids can't be matched to the old ones, the entries of `target`'s operations point
+/// at the wrong records, so they are dropped rather than undone.
+pub fn discard(conn: &Connection, target: Option<&str>) -> Result<()> {
+    forget_operations(conn, target)
+}
+
+/// Describes the operation `undo_last` would revert, without creating a journal for databases that have none.
+pub fn last_operation(db_path: &str) -> Result<Option<String>> {
+    let path = undo_db_path(db_path);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let conn = Connection::open(path)?;
+    conn.query_row(
+        "SELECT description || ' (' || created || ')' FROM operations ORDER BY id DESC LIMIT 1",
+        [],
+        |row| row.get(0),
+    ).optional()
+}
+
+/// Reverts the most recent operation in the journal of `db_path` and removes it from the journal,
+/// so calling it again steps further back. An operation made in a copy is reverted in that copy.
+pub fn undo_last(db_path: &str, reporter: &Reporter) -> Result<Option<String>, DbError> {
+    if !Path::new(&undo_db_path(db_path)).exists() {
+        return Ok(None);
+    }
+    let journal = Connection::open_in_memory()?;
+    attach(&journal, db_path)?;
+    let last = journal.query_row(
+        "SELECT id, description, target FROM journal.operations ORDER BY id DESC LIMIT 1",
+        [],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?)),
+    ).optional()?;
+    if let Some((operation, description, Some(target))) = &last {
+        // The copy was deleted, there is nothing left to put the records back into
+        if !Path::new(target).is_file() {
+            forget(&journal, *operation)?;
+            detach(&journal)?;
+            return Ok(Some(format!("{}, nothing restored as {} no longer exists", description, target)));
+        }
+    }
+    detach(&journal)?;
+    let Some((_, _, target)) = last else {
+        return Ok(None);
+    };
+
+    let mut conn = open_soundminer(target.as_deref().unwrap_or(db_path))?;
+    reporter.watch(&conn);
+    attach(&conn, db_path)?;
+
+    let result = undo_attached(&mut conn, reporter);
+    detach(&conn)?;
+    Ok(result?)
+}
+
+fn has_removed(conn: &Connection, operation: i64) -> Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM journal.sqlite_master WHERE type = 'table' AND name = ?1",
+        [format!("removed_{}", operation)],
+        |row| row.get(0),
+    )
+}
+
+/// The column that is an alias of the rowid, if the table has an INTEGER PRIMARY KEY.
+fn rowid_alias(conn: &Connection) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT CASE WHEN COUNT(*) = 1 AND UPPER(MAX(type)) = 'INTEGER' THEN MAX(name) END FROM pragma_table_info(?1) WHERE pk > 0",
+        [TABLE],
+        |row| row.get(0),
+    )
+}
+
+fn undo_attached(conn: &mut Connection, reporter: &Reporter) -> Result<Option<String>> {
+    let tx = conn.transaction()?;
+    let Some((operation, description, dirty)) = tx.query_row(
+        "SELECT id, description, dirty FROM journal.operations ORDER BY id DESC LIMIT 1",
+        [],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, bool>(2)?)),
+    ).optional()? else {
+        return Ok(None);
+    };
+
+    let main_columns = table_columns(&tx, "main")?;
+    let mut stmt = tx.prepare(
+        "SELECT row, column_name, old_value, old_dirty FROM journal.replaced WHERE operation = ?1 AND removed_by IS NULL ORDER BY rowid DESC"
+    )?;
+    let replaced = stmt.query_map([operation], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, rusqlite::types::Value>(2)?, row.get::<_, rusqlite::types::Value>(3)?))
+    })?.collect::<Result<Vec<_>>>()?;
+    drop(stmt);
+
+    // Newest first, so a row changed twice in one operation ends up with its oldest value
+    for (id, column, old_value, old_dirty) in replaced {
+        if !main_columns.contains(&column) {
+            return Err(rusqlite::Error::InvalidColumnName(column));
+        }
+        reporter.check_cancelled()?;
+        if dirty {
+            tx.prepare_cached(&format!("UPDATE main.{} SET \"{}\" = ?1, _Dirty = ?2 WHERE rowid = ?3", TABLE, column))?
+                .execute(rusqlite::params![old_value, old_dirty, id])?;
+        } else {
+            tx.prepare_cached(&format!("UPDATE main.{} SET \"{}\" = ?1 WHERE rowid = ?2", TABLE, column))?
+                .execute(rusqlite::params![old_value, id])?;
+        }
+    }
+
+    if has_removed(&tx, operation)? {
+        restore_removed(&tx, operation, reporter)?;
+    }
+
+    forget(&tx, operation)?;
+    reporter.check_cancelled()?;
+    tx.commit()?;
+    Ok(Some(description))
+}
+
+/// Puts the rows of a removal back. A row gets its old rowid while that is free, and a new one once
+/// VACUUM or Soundminer has handed it to another record. The older journal entries for the row follow it.
+fn restore_removed(tx: &Connection, operation: i64, reporter: &Reporter) -> Result<()> {
+    let removed_table = format!("removed_{}", operation);
+    let alias = rowid_alias(tx)?;
+    let mut stmt = tx.prepare(&format!("PRAGMA journal.table_info({})", removed_table))?;
+    // The alias holds the rowid as well, it is set through `rowid`
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .skip(1)
+        .filter(|column| !matches!((column, &alias), (Ok(column), Some(alias)) if column.eq_ignore_ascii_case(alias)))
+        .map(|column| column.map(|c| format!("\"{}\"", c)))
+        .collect::<Result<Vec<_>>>()?;
+    drop(stmt);
+
+    let old_rowids = tx.prepare(&format!("SELECT undo_rowid FROM journal.{}", removed_table))?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    let mut taken = tx.prepare(&format!("SELECT COUNT(*) > 0 FROM main.{} WHERE rowid = ?1", TABLE))?;
+    let mut insert = tx.prepare(&format!(
+        "INSERT INTO main.{} (rowid, {}) SELECT ?1, {} FROM journal.{} WHERE undo_rowid = ?2",
+        TABLE, columns.join(", "), columns.join(", "), removed_table
+    ))?;
+    let mut moved = tx.prepare("UPDATE journal.replaced SET row = ?1, removed_by = NULL WHERE removed_by = ?3 AND row = ?2")?;
+    for (counter, old_rowid) in old_rowids.into_iter().enumerate() {
+        if counter % PROGRESS_INTERVAL == 0 {
+            reporter.check_cancelled()?;
+        }
+        let free = old_rowid > 0 && !taken.query_row([old_rowid], |row| row.get::<_, bool>(0))?;
+        insert.execute(rusqlite::params![if free { Some(old_rowid) } else { None }, old_rowid])?;
+        moved.execute(rusqlite::params![tx.last_insert_rowid(), old_rowid, operation])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use super::*;
+    use crate::processing::tests::reporter;
+    use crate::processing::{remove_duplicates, smreplace_get, smreplace_process, thinned_db_path, FileRecord, Matcher};
+
+    fn library(name: &str, pathnames: &[&str]) -> String {
+        let rows: Vec<_> = pathnames.iter()
+            .map(|pathname| (Path::new(pathname).file_name().unwrap().to_str().unwrap(), *pathname, "", ""))
+            .collect();
+        crate::processing::tests::library(&format!("journal_{}", name), &rows)
+    }
+
+    fn pathnames(db_path: &str) -> Vec<(i64, String)> {
+        let conn = Connection::open(db_path).unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT rowid, pathname FROM {} ORDER BY rowid", TABLE)).unwrap();
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().collect::<Result<_>>().unwrap();
+        rows
+    }
+
+    fn records(ids: &[usize]) -> HashSet<FileRecord> {
+        ids.iter().map(|&id| FileRecord { id, filename: String::new(), duration: String::new(), keeper: None }).collect()
+    }
+
+    fn replace(db_path: &str, find: &str, replace: &str) {
+        let matcher = Matcher::new(find, false, true, false).unwrap();
+        let rows = smreplace_get(db_path, &matcher, replace, &["pathname".to_string()], &reporter()).unwrap();
+        smreplace_process(db_path, &rows, false, &reporter()).unwrap();
+    }
+
+    #[test]
+    fn undo_follows_records_renumbered_by_vacuum() {
+        let path = library("renumbered", &["/a/1.wav", "/a/2.wav", "/a/3.wav", "/a/4.wav"]);
+        replace(&path, "/a/", "/b/");
+        remove_duplicates(&path, &records(&[1, 2]), false, false, &reporter()).unwrap();
+        assert_eq!(pathnames(&path), vec![(1, "/b/3.wav".to_string()), (2, "/b/4.wav".to_string())]);
+
+        assert!(undo_last(&path, &reporter()).unwrap().is_some());
+        let mut restored = pathnames(&path).into_iter().map(|(_, pathname)| pathname).collect::<Vec<_>>();
+        restored.sort();
+        assert_eq!(restored, vec!["/b/1.wav", "/b/2.wav", "/b/3.wav", "/b/4.wav"]);
+
+        assert!(undo_last(&path, &reporter()).unwrap().is_some());
+        let mut restored = pathnames(&path).into_iter().map(|(_, pathname)| pathname).collect::<Vec<_>>();
+        restored.sort();
+        assert_eq!(restored, vec!["/a/1.wav", "/a/2.wav", "/a/3.wav", "/a/4.wav"]);
+        assert_eq!(undo_last(&path, &reporter()).unwrap(), None);
+    }
+
+    #[test]
+    fn undo_tells_apart_records_removed_from_the_same_rowid() {
+        let path = library("same_rowid", &["/a/1.wav", "/a/2.wav", "/a/3.wav", "/a/4.wav"]);
+        replace(&path, "/a/", "/b/");
+        remove_duplicates(&path, &records(&[2]), false, false, &reporter()).unwrap();
+        replace(&path, "/b/", "/c/");
+        // VACUUM moved /c/3.wav into rowid 2
+        remove_duplicates(&path, &records(&[2]), false, false, &reporter()).unwrap();
+        assert_eq!(pathnames(&path), vec![(1, "/c/1.wav".to_string()), (2, "/c/4.wav".to_string())]);
+
+        for _ in 0..4 {
+            assert!(undo_last(&path, &reporter()).unwrap().is_some());
+        }
+        let mut restored = pathnames(&path).into_iter().map(|(_, pathname)| pathname).collect::<Vec<_>>();
+        restored.sort();
+        assert_eq!(restored, vec!["/a/1.wav", "/a/2.wav", "/a/3.wav", "/a/4.wav"]);
+    }
+
+    #[test]
+    fn safe_removal_is_undone_in_the_thinned_copy() {
+        let path = library("safe", &["/a/1.wav", "/a/2.wav", "/a/3.wav"]);
+        remove_duplicates(&path, &records(&[2]), true, false, &reporter()).unwrap();
+        let thinned = thinned_db_path(&path);
+        assert_eq!(pathnames(&thinned).len(), 2);
+        assert!(!Path::new(&undo_db_path(&thinned)).exists());
+        assert!(last_operation(&path).unwrap().unwrap().contains(&thinned));
+
+        assert!(undo_last(&path, &reporter()).unwrap().is_some());
+        assert_eq!(pathnames(&thinned).len(), 3);
+        assert_eq!(pathnames(&path).len(), 3);
+        assert_eq!(last_operation(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn undo_skips_a_removal_from_a_deleted_copy() {
+        let path = library("deleted_copy", &["/a/1.wav", "/a/2.wav"]);
+        remove_duplicates(&path, &records(&[1]), true, false, &reporter()).unwrap();
+        fs::remove_file(thinned_db_path(&path)).unwrap();
+        let undone = undo_last(&path, &reporter()).unwrap().unwrap();
+        assert!(undone.contains("no longer exists"));
+        assert_eq!(last_operation(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn restored_records_get_new_rowids_once_theirs_are_taken() {
+        let path = library("reused", &["/a/1.wav", "/a/2.wav"]);
+        remove_duplicates(&path, &records(&[2]), false, false, &reporter()).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(&format!("INSERT INTO {} (rowid, filename, pathname) VALUES (2, 'new.wav', '/a/new.wav')", TABLE), []).unwrap();
+        drop(conn);
+
+        assert!(undo_last(&path, &reporter()).unwrap().is_some());
+        let rows = pathnames(&path);
+        assert_eq!(rows.len(), 3);
+        assert!(rows.contains(&(2, "/a/new.wav".to_string())));
+        assert!(rows.iter().any(|(rowid, pathname)| *rowid > 2 && pathname == "/a/2.wav"));
+    }
+}
@@ -3,4 +3,12 @@
 mod app;
 pub use app::TemplateApp;
 mod assets;
-mod processing;
\ No newline at end of file
+pub mod cli;
+pub mod engine;
+mod error;
+mod journal;
+mod processing;
+mod report;
+mod rules;
+mod schema;
+mod worker;
\ No newline at end of file
@@ -6,6 +6,14 @@
 fn main() -> eframe::Result {
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
 
+    // With a command on the command line, run headless instead of opening the window.
+    // Older macOS passes a -psn_ argument to apps launched from the Finder.
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && !args[1].starts_with("-psn_") {
+        attach_console();
+        std::process::exit(SMDB_Companion::cli::run(&args));
+    }
+
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([400.0, 300.0])
@@ -60,3 +68,20 @@ fn main() {
         }
     });
 }
+
+/// Release builds on Windows are GUI apps without a console, so the CLI writes to the one it was started from.
+#[cfg(all(windows, not(debug_assertions)))]
+fn attach_console() {
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    // Fails when there is no parent console, as when launched from Explorer, and output is lost as before
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(any(target_arch = "wasm32", all(windows, not(debug_assertions)))))]
+fn attach_console() {}
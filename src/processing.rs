@@ -1,175 +1,1368 @@
 #![allow(non_snake_case)]
 use rusqlite::{Connection, Result};
-use std::collections::HashSet;
-// use std::collections::HashMap;
-use std::env;
-use std::fs::{self, File};
-use std::io::{self, BufRead, Write};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::fs;
+use std::io;
 use std::path::Path;
-use std::error::Error;
+use std::sync::OnceLock;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::types::Value;
+use regex::{NoExpand, Regex, RegexBuilder};
 // use terminal_size::{Width, terminal_size};
 // use sqlx::{sqlite::SqlitePool, Row};
 
-use eframe::egui::{self, Ui, RichText};
-use crate::app::*;
+use crate::error::DbError;
+use crate::journal;
+use crate::schema::{Feature, Schema};
+use crate::report::{Reporter, Stage};
 
-const TABLE: &str = "justinmetadata";
+pub(crate) const TABLE: &str = "justinmetadata";
+/// Rowids bound per statement. SQLite before 3.32, which rusqlite may link from the system,
+/// refuses statements with more than 999 parameters.
+const BATCH_SIZE: usize = 999;
+pub(crate) const PROGRESS_INTERVAL: usize = 1000;
 
-pub fn smreplace_get(db_path: String, find: &mut String, column: &mut String ) -> usize {
-    let conn: Connection = Connection::open(db_path).unwrap(); 
+/// How the Find text is matched. Both the preview and `smreplace_process` use it, so they agree on which rows change.
+pub struct Matcher {
+    regex: Regex,
+    expand: bool,
+    whole_word: bool,
+}
+
+impl Matcher {
+    pub fn new(find: &str, use_regex: bool, match_case: bool, whole_word: bool) -> std::result::Result<Self, regex::Error> {
+        let pattern = if use_regex { find.to_string() } else { regex::escape(find) };
+        let regex = RegexBuilder::new(&pattern).case_insensitive(!match_case).build()?;
+        // Plain text mode replaces literally, only regex mode gives `$1` a meaning
+        Ok(Self { regex, expand: use_regex, whole_word })
+    }
 
-    let search_query = format!("SELECT COUNT(rowid) FROM {} WHERE {} LIKE ?1", TABLE, column);
-    // Prepare the SQL query with the search text
-    let stmt = conn.prepare(search_query.as_str()).ok();
-    let count = stmt.expect("Failed to prepare statement").query_row([format!("%{}%", find)], |row| row.get(0)).unwrap();
-    count
+    /// Borrowed when nothing matched, so callers can tell untouched values apart.
+    pub fn replace<'a>(&self, value: &'a str, replace: &str) -> Cow<'a, str> {
+        if !self.whole_word {
+            return if self.expand {
+                self.regex.replace_all(value, replace)
+            } else {
+                self.regex.replace_all(value, NoExpand(replace))
+            };
+        }
+        let mut replaced = String::new();
+        let mut last = 0;
+        let mut changed = false;
+        for caps in self.regex.captures_iter(value) {
+            let Some(found) = caps.get(0) else { continue; };
+            if !is_whole_word(value, found.start(), found.end()) {
+                continue;
+            }
+            replaced.push_str(&value[last..found.start()]);
+            if self.expand {
+                caps.expand(replace, &mut replaced);
+            } else {
+                replaced.push_str(replace);
+            }
+            last = found.end();
+            changed = true;
+        }
+        if !changed {
+            return Cow::Borrowed(value);
+        }
+        replaced.push_str(&value[last..]);
+        Cow::Owned(replaced)
+    }
+}
+
+/// A match is a whole word when no word character carries on past either end of it. Only ends that are
+/// word characters need a boundary, so text like the tag `-6030_` still matches after a letter.
+fn is_whole_word(value: &str, start: usize, end: usize) -> bool {
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    let found = &value[start..end];
+    let starts_clear = match (found.chars().next(), value[..start].chars().next_back()) {
+        (Some(first), Some(before)) => !(word(first) && word(before)),
+        _ => true,
+    };
+    let ends_clear = match (found.chars().next_back(), value[end..].chars().next()) {
+        (Some(last), Some(after)) => !(word(last) && word(after)),
+        _ => true,
+    };
+    starts_clear && ends_clear
 }
 
-// pub async fn smreplace_get(db_path: &str, find: &str, column: &str) -> Result<usize, sqlx::Error> {
-//     // Create a connection pool
-//     let pool = SqlitePool::connect(db_path).await?;
+/// Storage classes Find and Replace reads, blobs have no text to search.
+const SEARCHED_TYPES: &str = "('text', 'integer', 'real')";
+
+/// A matched value and what it would become after the replace.
+#[derive(Clone, Debug)]
+pub struct ReplaceRow {
+    pub id: i64,
+    pub filename: String,
+    pub column: String,
+    pub old: String,
+    pub new: String,
+    pub include: bool,
+}
+
+/// SQLite's LIKE and REPLACE disagree on case, so matching runs in Rust over every text value in each column.
+/// In regex mode `replace` may refer to capture groups as `$1` or `${name}`.
+pub fn smreplace_get(db_path: &str, find: &Matcher, replace: &str, columns: &[String], reporter: &Reporter) -> Result<Vec<ReplaceRow>, DbError> {
+    let conn = open_soundminer(db_path)?;
+    Schema::read(&conn)?.require(Feature::Replace)?;
+    for column in columns {
+        check_column(&conn, column)?;
+    }
+    reporter.watch(&conn);
+
+    let mut total = 0;
+    for column in columns {
+        let query = format!("SELECT COUNT(rowid) FROM {} WHERE typeof(\"{}\") IN {}", TABLE, column, SEARCHED_TYPES);
+        total += conn.query_row(&query, [], |row| row.get::<_, usize>(0))?;
+    }
+
+    let mut matches = Vec::new();
+    let mut counter = 0;
+    for column in columns {
+        find_in_column(&conn, find, replace, column, &mut matches, &mut counter, total, reporter)?;
+    }
+    reporter.progress(Stage::Find, total, total);
+    Ok(matches)
+}
+
+/// Progress is reported against `total` rows scanned, pass 0 when the caller reports its own.
+#[allow(clippy::too_many_arguments)]
+fn find_in_column(conn: &Connection, find: &Matcher, replace: &str, column: &str, matches: &mut Vec<ReplaceRow>, counter: &mut usize, total: usize, reporter: &Reporter) -> Result<()> {
+    // Numbers are searched as the text Soundminer shows for them
+    let query = format!("SELECT rowid, filename, CAST(\"{}\" AS TEXT) FROM {} WHERE typeof(\"{}\") IN {}", column, TABLE, column, SEARCHED_TYPES);
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if *counter % PROGRESS_INTERVAL == 0 {
+            reporter.check_cancelled()?;
+            if total > 0 {
+                reporter.progress(Stage::Find, *counter, total);
+            }
+        }
+        let old: String = row.get(2)?;
+        if let Cow::Owned(new) = find.replace(&old, replace) {
+            matches.push(ReplaceRow {
+                id: row.get(0)?,
+                filename: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                column: column.to_string(),
+                old,
+                new,
+                include: true,
+            });
+        }
+        *counter += 1;
+    }
+    Ok(())
+}
 
-//     // Create the search query using column
-//     let search_query = format!(
-//         "SELECT COUNT(rowid) FROM {} WHERE {} LIKE ?",
-//         TABLE, column
-//     );
+/// How many rows matched in each column, in the order the columns were searched.
+pub fn replace_counts(rows: &[ReplaceRow]) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for row in rows.iter().filter(|row| row.include) {
+        match counts.iter_mut().find(|(column, _)| *column == row.column) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((row.column.clone(), 1)),
+        }
+    }
+    counts
+}
 
-//     // Execute the query and get the result
-//     let row = sqlx::query(&search_query)
-//         .bind(format!("%{}%", find)) // Bind the search text
-//         .fetch_one(&pool)
-//         .await?;
+/// Writes the included rows from a preview, across all their columns in a single transaction.
+/// A value that changed since the preview is left alone. The old values go to the undo journal.
+pub fn smreplace_process(db_path: &str, rows: &[ReplaceRow], dirty: bool, reporter: &Reporter) -> Result<usize, DbError> {
+    let mut conn = open_soundminer(db_path)?;
+    require_replace(&conn, dirty)?;
+    for (column, _) in replace_counts(rows) {
+        check_column(&conn, &column)?;
+    }
+    reporter.watch(&conn);
+    journal::attach(&conn, db_path)?;
 
-//     // Extract the count from the first column
-//     let count: i64 = row.get(0);
+    let result = replace_rows(&mut conn, rows, dirty, reporter);
+    journal::detach(&conn)?;
+    Ok(result?)
+}
 
-//     // Convert to usize
-//     Ok(usize::from_str(&count.to_string()).unwrap())
-// }
+fn replace_rows(conn: &mut Connection, rows: &[ReplaceRow], dirty: bool, reporter: &Reporter) -> Result<usize> {
+    let columns: Vec<String> = replace_counts(rows).into_iter().map(|(column, _)| column).collect();
+    let rows: Vec<&ReplaceRow> = rows.iter().filter(|row| row.include).collect();
+    let tx = conn.transaction()?;
+    let operation = journal::begin(&tx, &format!("Replace in {} of {} records", columns.join(", "), rows.len()), dirty, None)?;
+    let count = write_replaced(&tx, operation, &rows, dirty, Some(Stage::Find), reporter)?;
+    if count == 0 {
+        journal::forget(&tx, operation)?;
+    }
+    // A cancelled replace is dropped without committing, which rolls it back
+    reporter.check_cancelled()?;
+    tx.commit()?;
+    Ok(count)
+}
 
-pub fn smreplace_process(db_path: String, find: &mut String, replace: &mut String, column: &mut String, dirty: bool ) {
-    let conn: Connection = Connection::open(db_path).unwrap(); 
+/// Journals and updates each row, returning how many were changed.
+fn write_replaced(tx: &Connection, operation: i64, rows: &[&ReplaceRow], dirty: bool, progress: Option<Stage>, reporter: &Reporter) -> Result<usize> {
     let dirty_text = if dirty { ", _Dirty = 1" } else { "" };
-   
-    let replace_query = format!("UPDATE {} SET {} = REPLACE({}, '{}', '{}'){} WHERE {} LIKE '%{}%'", TABLE, column, column, find, replace, dirty_text, column, find);
-    conn.execute(replace_query.as_str(), []).ok();
+    let mut count = 0;
+    for (counter, row) in rows.iter().enumerate() {
+        if counter % PROGRESS_INTERVAL == 0 {
+            reporter.check_cancelled()?;
+            if let Some(stage) = progress {
+                reporter.progress(stage, counter, rows.len());
+            }
+        }
+        if journal::record_replaced(tx, operation, &row.column, row.id, &row.old, dirty)? {
+            let update_query = format!("UPDATE {} SET \"{}\" = ?1{} WHERE rowid = ?2", TABLE, row.column, dirty_text);
+            count += tx.prepare_cached(&update_query)?.execute(rusqlite::params![row.new, row.id])?;
+        }
+    }
+    Ok(count)
+}
+
+/// One line of a batch mapping file, `count` is filled in by `smreplace_batch_counts`.
+#[derive(Clone, Debug)]
+pub struct BatchRule {
+    pub line: usize,
+    pub column: String,
+    pub find: String,
+    pub replace: String,
+    pub regex: bool,
+    pub count: Option<usize>,
+}
+
+/// Reads `column,find,replace[,regex]` lines. Fields may be quoted to hold commas, quotes or line breaks,
+/// and a first line starting with `column` is taken as a header.
+pub fn parse_batch_csv(text: &str) -> std::result::Result<Vec<BatchRule>, String> {
+    let mut rules = Vec::new();
+    for (line, fields) in parse_csv_records(text)? {
+        if fields.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+        if rules.is_empty() && fields[0].trim().eq_ignore_ascii_case("column") {
+            continue;
+        }
+        if fields.len() < 3 || fields.len() > 4 {
+            return Err(format!("Line {}: expected column,find,replace[,regex] but found {} fields", line, fields.len()));
+        }
+        let regex = match fields.get(3).map(|field| field.trim().to_lowercase()).as_deref() {
+            None | Some("") | Some("0") | Some("false") | Some("no") | Some("n") => false,
+            Some("1") | Some("true") | Some("yes") | Some("y") | Some("regex") => true,
+            Some(other) => return Err(format!("Line {}: '{}' is not true or false", line, other)),
+        };
+        if fields[1].is_empty() {
+            return Err(format!("Line {}: find text is empty", line));
+        }
+        rules.push(BatchRule {
+            line,
+            column: fields[0].trim().to_string(),
+            find: fields[1].clone(),
+            replace: fields[2].clone(),
+            regex,
+            count: None,
+        });
+    }
+    Ok(rules)
+}
 
+/// Splits CSV text into records, each paired with the line it starts on.
+fn parse_csv_records(text: &str) -> std::result::Result<Vec<(usize, Vec<String>)>, String> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut start_line = 1;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes && chars.peek() == Some(&'\n') => {}
+            '\n' if !in_quotes => {
+                fields.push(std::mem::take(&mut field));
+                records.push((start_line, std::mem::take(&mut fields)));
+                line += 1;
+                start_line = line;
+            }
+            _ => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if in_quotes {
+        return Err(format!("Line {}: quoted field is never closed", start_line));
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        records.push((start_line, fields));
+    }
+    Ok(records)
+}
+
+/// Counts the matches of each rule on its own against the database as it is now.
+pub fn smreplace_batch_counts(db_path: &str, rules: &[(BatchRule, Matcher)], reporter: &Reporter) -> Result<Vec<usize>, DbError> {
+    let conn = open_soundminer(db_path)?;
+    Schema::read(&conn)?.require(Feature::Replace)?;
+    for (rule, _) in rules {
+        check_column(&conn, &rule.column)?;
+    }
+    reporter.watch(&conn);
+
+    let mut counts = Vec::new();
+    for (index, (rule, find)) in rules.iter().enumerate() {
+        reporter.progress(Stage::Batch, index, rules.len());
+        let mut matches = Vec::new();
+        find_in_column(&conn, find, &rule.replace, &rule.column, &mut matches, &mut 0, 0, reporter)?;
+        counts.push(matches.len());
+    }
+    reporter.progress(Stage::Batch, rules.len(), rules.len());
+    Ok(counts)
+}
+
+/// Applies every rule in order in a single transaction, so each rule sees the changes of the ones before it.
+/// The whole batch is one entry in the undo journal.
+pub fn smreplace_batch_process(db_path: &str, rules: &[(BatchRule, Matcher)], dirty: bool, reporter: &Reporter) -> Result<usize, DbError> {
+    let mut conn = open_soundminer(db_path)?;
+    require_replace(&conn, dirty)?;
+    for (rule, _) in rules {
+        check_column(&conn, &rule.column)?;
+    }
+    reporter.watch(&conn);
+    journal::attach(&conn, db_path)?;
+
+    let result = batch_rows(&mut conn, rules, dirty, reporter);
+    journal::detach(&conn)?;
+    Ok(result?)
+}
+
+fn batch_rows(conn: &mut Connection, rules: &[(BatchRule, Matcher)], dirty: bool, reporter: &Reporter) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let operation = journal::begin(&tx, &format!("Batch replace of {} rules", rules.len()), dirty, None)?;
+    let mut count = 0;
+    for (index, (rule, find)) in rules.iter().enumerate() {
+        reporter.progress(Stage::Batch, index, rules.len());
+        let mut matches = Vec::new();
+        find_in_column(&tx, find, &rule.replace, &rule.column, &mut matches, &mut 0, 0, reporter)?;
+        let rows: Vec<&ReplaceRow> = matches.iter().collect();
+        count += write_replaced(&tx, operation, &rows, dirty, None, reporter)?;
+    }
+    reporter.progress(Stage::Batch, rules.len(), rules.len());
+    if count == 0 {
+        journal::forget(&tx, operation)?;
+    }
+    // A cancelled batch is dropped without committing, which rolls back every rule
+    reporter.check_cancelled()?;
+    tx.commit()?;
+    Ok(count)
 }
 
+fn require_replace(conn: &Connection, dirty: bool) -> Result<(), DbError> {
+    let schema = Schema::read(conn)?;
+    schema.require(Feature::Replace)?;
+    if dirty {
+        schema.require(Feature::Dirty)?;
+    }
+    Ok(())
+}
 
-pub fn gather_duplicates(main: &mut Config, group: &mut Config, deep: &mut Config, tags: &mut Config, compare: &mut Config) {
-    let mut source_db_path = String::new();
-    if let Some(path) = &main.option {
-        source_db_path = path.clone();
+/// User chosen column names can't be bound as parameters, so only known columns make it into SQL.
+fn check_column(conn: &Connection, column: &str) -> Result<()> {
+    if editable_columns(conn)?.iter().any(|c| c == column) {
+        Ok(())
+    } else {
+        Err(rusqlite::Error::InvalidColumnName(column.to_string()))
     }
-    let source_db_name = source_db_path.split('/').last().unwrap();
+}
 
-    main.status = format!("Opening {}", source_db_name);
-    let mut conn = Connection::open(&source_db_path).unwrap(); 
+fn editable_columns(conn: &Connection) -> Result<Vec<String>> {
+    let mut columns: Vec<String> = table_columns(conn, "main")?
+        .into_iter()
+        .filter(|c| !c.starts_with('_'))
+        .collect();
+    columns.sort();
+    Ok(columns)
+}
 
-    // if main.search {
-    //     main.working = true;
-    //     group.records = gather_duplicate_filenames_in_database(&mut conn, &config.group_sort, config.group_null, config.verbose)?;
-    //     main.records.extend(group.records);
-    //     main.working = false;
-    // }
 
-    // if deep.search {
-    //     deep.working = true;
-    //     deep.records = gather_records_with_trailing_numbers(&mut conn, total_records)?;
-    //     main.records.extend(deep.records);
-    //     deep.working = false;
-    // }
+#[derive(Clone, Debug,)]
+pub struct FileRecord {
+    pub id: usize,
+    pub filename: String,
+    pub duration: String,
+    /// The record ranked above this one, for searches that rank duplicates against each other.
+    pub keeper: Option<usize>,
+}
 
-    if tags.search {
-        main.status = format!("Searching for tags");
-        tags.working = true;
-        tags.status = format!{"Found {} records with matching tags", tags.records.len()};
-        gather_filenames_with_tags(&mut conn, tags).ok();
-        tags.working = false;
-        tags.status = format!{"Found {} records with matching tags", tags.records.len()};
-        main.records.extend(tags.records.clone());
+// A record is its rowid, so one marked by several searches is only removed once
+impl PartialEq for FileRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
     }
-    
+}
+impl Eq for FileRecord {}
+impl Hash for FileRecord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
 
-    // if let Some(compare_db_path) = config.compare_db {
-    //     let compare_conn = Connection::open(&compare_db_path)?; 
-    //     let ids_from_compare_db = gather_compare_database_overlaps(&conn, &compare_conn)?;
-    //     main.records.extend(ids_from_compare_db);
-    // }
+pub fn gather_duplicate_filenames_in_database(conn: &mut Connection, order: &[String], group_by: Option<&str>, group_null: bool, reporter: &Reporter) -> Result<HashSet<FileRecord>> {
+    // Every record sharing a filename (within its group) is ranked by the keeper priority order,
+    // the top ranked record is kept and the rest are returned for removal.
+    let (partition_by, where_clause) = filename_partition(conn, group_by, group_null)?;
+    rank_duplicates(conn, &partition_by, TABLE, &where_clause, order, Stage::Group, reporter)
+}
 
+/// The PARTITION BY and WHERE clauses that group records by filename, within `group_by` when set.
+fn filename_partition(conn: &Connection, group_by: Option<&str>, group_null: bool) -> Result<(String, String)> {
+    Ok(match group_by {
+        Some(group) => {
+            if !table_columns(conn, "main")?.iter().any(|c| c == group) {
+                return Err(rusqlite::Error::InvalidColumnName(group.to_string()));
+            }
+            if group_null {
+                // Records without a group entry are processed together
+                (
+                    format!("COALESCE(\"{}\", ''), filename", group),
+                    "WHERE filename IS NOT NULL AND filename != ''".to_string(),
+                )
+            } else {
+                // Records without a group entry are skipped
+                (
+                    format!("\"{}\", filename", group),
+                    format!("WHERE filename IS NOT NULL AND filename != '' AND \"{}\" IS NOT NULL AND \"{}\" != ''", group, group),
+                )
+            }
+        }
+        None => ("filename".to_string(), "WHERE filename IS NOT NULL AND filename != ''".to_string()),
+    })
+}
 
+pub fn gather_records_with_trailing_numbers(conn: &mut Connection, order: &[String], reporter: &Reporter) -> Result<HashSet<FileRecord>> {
+    let total = get_record_count(conn)?;
+    let mut file_groups: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+    {
+        reporter.status(Stage::Deep, "Analyzing Records");
+        let mut stmt = conn.prepare(&format!("SELECT rowid, filename FROM {} WHERE filename IS NOT NULL", TABLE))?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, usize>(0)?, row.get::<_, String>(1)?)))?;
+        for (counter, row) in rows.enumerate() {
+            if counter % PROGRESS_INTERVAL == 0 {
+                reporter.check_cancelled()?;
+                reporter.progress(Stage::Deep, counter, total);
+            }
+            let (id, filename) = row?;
+            file_groups
+                .entry(get_root_filename(&filename))
+                .or_default()
+                .push((id, filename));
+        }
+    }
 
-    if main.records.is_empty() {
-        main.status = format!("No records marked for removal.");
-       
+    // Only groups where a suffixed variant sits beside another name are deep dive candidates,
+    // plain duplicates of a single filename are left to the basic search.
+    file_groups.retain(|_, records| {
+        records.iter().any(|(_, filename)| filename != &records[0].1)
+    });
+
+    let tx = conn.transaction()?;
+    // WITHOUT ROWID keeps a bare `rowid` in an order line pointing at the record
+    tx.execute("CREATE TEMP TABLE IF NOT EXISTS deep_roots (id INTEGER PRIMARY KEY, root TEXT) WITHOUT ROWID", [])?;
+    tx.execute("DELETE FROM temp.deep_roots", [])?;
+    {
+        let mut insert = tx.prepare("INSERT INTO temp.deep_roots (id, root) VALUES (?1, ?2)")?;
+        for (root, records) in &file_groups {
+            for (id, _) in records {
+                insert.execute(rusqlite::params![id, root])?;
+            }
+        }
     }
+    tx.commit()?;
 
-    main.status = format!("Marked {} total records for removal.", main.records.len());
+    // When the priority order can't separate them, keep the unsuffixed original
+    let mut order = order.to_vec();
+    order.push("CASE WHEN filename = deep_roots.root THEN 0 ELSE 1 END ASC".to_string());
 
+    reporter.status(Stage::Deep, "Processing which filenames are best for removal");
+    let file_records = rank_duplicates(
+        conn,
+        "deep_roots.root",
+        &format!("{} JOIN temp.deep_roots ON {}.rowid = deep_roots.id", TABLE, TABLE),
+        "",
+        &order,
+        Stage::Deep,
+        reporter,
+    );
+    conn.execute("DROP TABLE IF EXISTS temp.deep_roots", [])?;
+    file_records
 }
 
-pub fn gather_filenames_with_tags(conn: &mut Connection, tags: &mut Config) -> Result<()> {
-    // tags.status = format!("Searching for filenames containing tags");
-    // let mut file_records = HashSet::new();
+/// Strips Pro Tools/Soundminer suffixes such as `.1`, `.1.2.3` or `.M` from before the extension.
+pub fn get_root_filename(filename: &str) -> String {
+    static ROOT_FILENAME: OnceLock<Regex> = OnceLock::new();
+    let re = ROOT_FILENAME.get_or_init(|| {
+        Regex::new(r"^(?P<base>.+?)(?:\.(?:\d+|M))+(?P<ext>\.[A-Za-z0-9]+)$").expect("the root filename pattern is valid")
+    });
+    match re.captures(filename) {
+        Some(caps) => format!("{}{}", &caps["base"], &caps["ext"]),
+        None => filename.to_string(),
+    }
+}
+
+fn rank_duplicates(conn: &mut Connection, partition_by: &str, from: &str, where_clause: &str, order: &[String], stage: Stage, reporter: &Reporter) -> Result<HashSet<FileRecord>> {
+    let total: usize = conn.query_row(
+        &format!("SELECT COALESCE(SUM(n - 1), 0) FROM (SELECT COUNT(*) AS n FROM {} {} GROUP BY {})", from, where_clause, partition_by),
+        [],
+        |row| row.get(0),
+    )?;
+    reporter.progress(stage, 0, total);
+
+    // Ties fall to the lowest rowid, as in read_member_ranks. The deep dive joins
+    // another table, so the rowid must say whose it is.
+    let tiebreak = format!("{}.rowid ASC", TABLE);
+    let order_clause = order.iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .chain([tiebreak.as_str()])
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let sql = format!(
+        "
+        WITH ranked AS (
+            SELECT
+                {}.rowid AS id,
+                filename,
+                duration,
+                ROW_NUMBER() OVER ranking AS rn,
+                FIRST_VALUE({}.rowid) OVER ranking AS keeper
+            FROM {}
+            {}
+            WINDOW ranking AS (
+                PARTITION BY {}
+                ORDER BY {}
+            )
+        )
+        SELECT id, filename, duration, keeper FROM ranked WHERE rn > 1
+        ",
+        TABLE, TABLE, from, where_clause, partition_by, order_clause
+    );
 
-    for tag in &tags.list {
-        let query = format!("SELECT rowid, filename, duration FROM justinmetadata WHERE filename LIKE '%' || ? || '%'");
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], |row| {
+        Ok(FileRecord {
+            id: row.get(0)?,
+            filename: row.get(1)?,
+            duration: row.get(2)?,
+            keeper: row.get(3)?,
+        })
+    })?;
+
+    let mut file_records = HashSet::new();
+    for (counter, file_record) in rows.enumerate() {
+        if counter % PROGRESS_INTERVAL == 0 {
+            reporter.check_cancelled()?;
+            reporter.progress(stage, counter, total);
+        }
+        file_records.insert(file_record?);
+    }
+    reporter.progress(stage, total, total);
+    Ok(file_records)
+}
+
+/// Marks every record whose filename, and any `match_columns`, also appears in the comparison database.
+/// ATTACH would create a missing file, so the comparison database has to exist already.
+pub fn gather_compare_database_overlaps(conn: &Connection, compare_db_path: &str, match_columns: &[String], reporter: &Reporter) -> Result<HashSet<FileRecord>, DbError> {
+    if !Path::new(compare_db_path).is_file() {
+        return Err(DbError::Io(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", compare_db_path))));
+    }
+    let main_path: String = conn.query_row("SELECT file FROM pragma_database_list WHERE name = 'main'", [], |row| row.get(0))?;
+    // An in-memory main database has no file to be the same as
+    if let (Ok(main), Ok(compare)) = (fs::canonicalize(main_path), fs::canonicalize(compare_db_path)) {
+        if main == compare {
+            return Err(DbError::CompareWithItself);
+        }
+    }
+
+    reporter.progress(Stage::Compare, 0, 1);
+    conn.execute("ATTACH DATABASE ?1 AS compare", [compare_db_path])?;
+    let file_records = gather_attached_overlaps(conn, match_columns);
+    conn.execute("DETACH DATABASE compare", [])?;
+    reporter.progress(Stage::Compare, 1, 1);
+    Ok(file_records?)
+}
+
+fn gather_attached_overlaps(conn: &Connection, match_columns: &[String]) -> Result<HashSet<FileRecord>> {
+    let target_columns = table_columns(conn, "main")?;
+    let compare_columns = table_columns(conn, "compare")?;
+
+    let mut columns = vec!["filename".to_string()];
+    for column in match_columns {
+        if !target_columns.contains(column) || !compare_columns.contains(column) {
+            return Err(rusqlite::Error::InvalidColumnName(column.clone()));
+        }
+        if !columns.contains(column) {
+            columns.push(column.clone());
+        }
+    }
+    let columns = columns.iter().map(|c| format!("\"{}\"", c)).collect::<Vec<_>>().join(", ");
+
+    let sql = format!(
+        "SELECT rowid, filename, duration FROM main.{} WHERE ({}) IN (SELECT {} FROM compare.{})",
+        TABLE, columns, columns, TABLE
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], |row| {
+        Ok(FileRecord {
+            id: row.get(0)?,
+            filename: row.get(1)?,
+            duration: row.get(2)?,
+            keeper: None,
+        })
+    })?;
+
+    let mut file_records = HashSet::new();
+    for file_record in rows {
+        file_records.insert(file_record?);
+    }
+    Ok(file_records)
+}
+
+pub(crate) fn table_columns(conn: &Connection, schema: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA {}.table_info({})", schema, TABLE))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(columns)
+}
+
+pub fn gather_filenames_with_tags(conn: &mut Connection, tags: &[String], reporter: &Reporter) -> Result<HashSet<FileRecord>> {
+    let mut file_records = HashSet::new();
+
+    for (counter, tag) in tags.iter().enumerate() {
+        reporter.check_cancelled()?;
+        reporter.progress(Stage::Tags, counter, tags.len());
+        let query = format!("SELECT rowid, filename, duration FROM {} WHERE filename LIKE '%' || ? || '%'", TABLE);
         let mut stmt = conn.prepare(&query)?;
         let rows = stmt.query_map([tag.clone()], |row| {
             Ok(FileRecord {
                 id: row.get(0)?,
                 filename: row.get(1)?,
                 duration: row.get(2)?,
+                keeper: None,
             })
         })?;
 
         for file_record in rows {
-            let file_record = file_record?;
-            tags.records.insert(file_record);
+            file_records.insert(file_record?);
         }
     }
-    // tags.records = file_records;
-    Ok(())
-    // tags.status = format!("{} total records containing tags marked for deletion", tags.records.len());
+    reporter.progress(Stage::Tags, tags.len(), tags.len());
+    Ok(file_records)
+}
+
+/// One record of a duplicate group, with the columns a user needs to judge which copy to keep.
+#[derive(Clone, Debug)]
+pub struct ResultRow {
+    pub id: usize,
+    pub filename: String,
+    pub pathname: String,
+    pub duration: String,
+    pub channels: String,
+    pub sample_rate: String,
+    pub description: String,
+    /// Ranked first by the keeper order.
+    pub keeper: bool,
+    pub remove: bool,
+    /// Why a record lost to its keeper: the first order rule that ranked them apart.
+    pub reason: String,
+}
+
+impl ResultRow {
+    pub fn record(&self) -> FileRecord {
+        FileRecord { id: self.id, filename: self.filename.clone(), duration: self.duration.clone(), keeper: None }
+    }
+}
+
+/// A keeper followed by the records marked in its favour. Records marked without a keeper,
+/// by the tag search or a database comparison, are a group of their own.
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    pub rows: Vec<ResultRow>,
+}
+
+/// Columns shown for each record, a column the database lacks shows as empty.
+const RESULT_COLUMNS: [&str; 5] = ["pathname", "duration", "channels", "sampleRate", "Description"];
+
+/// Groups marked records under their keepers, with every record's details read from the database.
+/// Each group is listed in `order` rank, and each loser is told which rule put it below the keeper.
+pub fn load_duplicate_groups(db_path: &str, records: &HashSet<FileRecord>, order: &[String], reporter: &Reporter) -> Result<Vec<DuplicateGroup>, DbError> {
+    let conn = open_soundminer(db_path)?;
+    reporter.watch(&conn);
+    let schema = Schema::read(&conn)?;
+    let columns = RESULT_COLUMNS.iter()
+        .map(|column| if schema.has_column(column) { format!("\"{}\"", column) } else { "NULL".to_string() })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut ids: Vec<usize> = records.iter().flat_map(|record| [Some(record.id), record.keeper]).flatten().collect();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut details: HashMap<usize, ResultRow> = HashMap::new();
+    for chunk in ids.chunks(BATCH_SIZE) {
+        reporter.check_cancelled()?;
+        let placeholders = chunk.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let query = format!("SELECT rowid, filename, {} FROM {} WHERE rowid IN ({})", columns, TABLE, placeholders);
+        let mut stmt = conn.prepare(&query)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(chunk), |row| {
+            let text = |index: usize| -> Result<String> { Ok(display_value(row.get(index)?)) };
+            Ok(ResultRow {
+                id: row.get(0)?,
+                filename: text(1)?,
+                pathname: text(2)?,
+                duration: text(3)?,
+                channels: text(4)?,
+                sample_rate: text(5)?,
+                description: text(6)?,
+                keeper: false,
+                remove: false,
+                reason: String::new(),
+            })
+        })?;
+        for row in rows {
+            let row = row?;
+            details.insert(row.id, row);
+        }
+    }
+
+    let marked: HashSet<usize> = records.iter().map(|record| record.id).collect();
+    let mut ranked: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut alone = Vec::new();
+    for record in records {
+        match record.keeper {
+            Some(keeper) => ranked.entry(keeper).or_default().push(record.id),
+            None => alone.push(record.id),
+        }
+    }
+
+    let rules: Vec<OrderRule> = order.iter().map(|line| line.trim()).filter(|line| !line.is_empty()).map(OrderRule::parse).collect();
+    let ranks = rank_members(&conn, &rules, &ranked, reporter)?;
+
+    let row = |id: usize, keeper: bool| details.get(&id).cloned().map(|row| ResultRow { keeper, remove: marked.contains(&id), ..row });
+    let mut groups = Vec::new();
+    // A keeper that another search marked is listed with its group rather than on its own
+    alone.retain(|id| !ranked.contains_key(id));
+    for (keeper, mut losers) in ranked {
+        let position = |id: &usize| ranks.get(&(keeper, *id)).map_or(usize::MAX, |rank| rank.position);
+        losers.sort_unstable_by_key(|id| (position(id), *id));
+        let mut rows: Vec<ResultRow> = std::iter::once(row(keeper, true))
+            .chain(losers.into_iter().map(|id| row(id, false)))
+            .flatten()
+            .collect();
+        if let Some((keeper, losers)) = rows.split_first_mut() {
+            let keeper_rank = ranks.get(&(keeper.id, keeper.id));
+            for loser in losers {
+                loser.reason = explain_rank(&rules, keeper_rank, ranks.get(&(keeper.id, loser.id)), &keeper.filename, &loser.filename);
+            }
+        }
+        groups.push(DuplicateGroup { rows });
+    }
+    groups.extend(alone.into_iter().filter_map(|id| row(id, false)).map(|row| DuplicateGroup { rows: vec![row] }));
+    groups.retain(|group| !group.rows.is_empty());
+    groups.sort_by(|a, b| a.rows[0].filename.cmp(&b.rows[0].filename).then(a.rows[0].id.cmp(&b.rows[0].id)));
+    Ok(groups)
+}
+
+/// A keeper order line, with the expression it ranks by when the line holds a single term.
+pub struct OrderRule {
+    pub line: String,
+    /// `None` for lines sorting by several comma separated terms, which have no one value to show.
+    pub expression: Option<String>,
+}
+
+impl OrderRule {
+    pub fn parse(line: &str) -> Self {
+        static DIRECTION: OnceLock<Regex> = OnceLock::new();
+        let re = DIRECTION.get_or_init(|| {
+            Regex::new(r"(?is)^(?P<expression>.*?)(?:\s+(?:ASC|DESC))?(?:\s+NULLS\s+(?:FIRST|LAST))?\s*$").expect("the order direction pattern is valid")
+        });
+        let line = line.trim();
+        let expression = re.captures(line)
+            .filter(|_| !has_top_level_comma(line))
+            .map(|caps| caps["expression"].to_string());
+        Self { line: line.to_string(), expression }
+    }
+}
+
+/// Where SQLite placed a record within its keeper's group, and how each rule on its own ranked it there.
+struct MemberRank {
+    position: usize,
+    ranks: Vec<i64>,
+    values: Vec<Value>,
+}
+
+/// Ranks each keeper's group by the whole order and by each rule alone, letting SQLite compare the records
+/// so collations and type affinities count exactly as they did in the search. Keyed by (keeper, member).
+fn rank_members(conn: &Connection, rules: &[OrderRule], ranked: &HashMap<usize, Vec<usize>>, reporter: &Reporter) -> Result<HashMap<(usize, usize), MemberRank>, DbError> {
+    // WITHOUT ROWID keeps a bare `rowid` in an order line pointing at the record
+    conn.execute_batch(
+        "DROP TABLE IF EXISTS temp.result_members;
+         CREATE TEMP TABLE result_members (
+            _smdb_keeper INTEGER NOT NULL,
+            _smdb_member INTEGER NOT NULL,
+            PRIMARY KEY (_smdb_keeper, _smdb_member)
+         ) WITHOUT ROWID;"
+    )?;
+    let ranks = read_member_ranks(conn, rules, ranked, reporter);
+    conn.execute("DROP TABLE IF EXISTS temp.result_members", [])?;
+    ranks
+}
+
+fn read_member_ranks(conn: &Connection, rules: &[OrderRule], ranked: &HashMap<usize, Vec<usize>>, reporter: &Reporter) -> Result<HashMap<(usize, usize), MemberRank>, DbError> {
+    let mut insert = conn.prepare("INSERT OR IGNORE INTO temp.result_members (_smdb_keeper, _smdb_member) VALUES (?1, ?2)")?;
+    for (counter, (keeper, losers)) in ranked.iter().enumerate() {
+        if counter % PROGRESS_INTERVAL == 0 {
+            reporter.check_cancelled()?;
+        }
+        for member in std::iter::once(keeper).chain(losers) {
+            insert.execute([keeper, member])?;
+        }
+    }
+
+    let window = |order: &str| format!("OVER (PARTITION BY m._smdb_keeper ORDER BY {})", order);
+    let full_order = rules.iter()
+        .map(|rule| rule.line.as_str())
+        .chain(std::iter::once(&*format!("{}.rowid ASC", TABLE)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let columns = std::iter::once(format!("ROW_NUMBER() {}", window(&full_order)))
+        .chain(rules.iter().map(|rule| format!("DENSE_RANK() {}", window(&rule.line))))
+        .chain(rules.iter().map(|rule| match &rule.expression {
+            Some(expression) => format!("({})", expression),
+            None => "NULL".to_string(),
+        }))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let query = format!(
+        "SELECT m._smdb_keeper, m._smdb_member, {} FROM {} JOIN temp.result_members m ON m._smdb_member = {}.rowid",
+        columns, TABLE, TABLE
+    );
+
+    reporter.check_cancelled()?;
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query([])?;
+    let mut ranks = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let ranks_of = (0..rules.len()).map(|index| row.get(3 + index)).collect::<Result<Vec<i64>>>()?;
+        let values = (0..rules.len()).map(|index| row.get(3 + rules.len() + index)).collect::<Result<Vec<Value>>>()?;
+        let rank = MemberRank { position: row.get(2)?, ranks: ranks_of, values };
+        ranks.insert((row.get(0)?, row.get(1)?), rank);
+    }
+    Ok(ranks)
+}
+
+/// Names the first rule that ranked the keeper and the loser apart, with the values each got.
+fn explain_rank(rules: &[OrderRule], keeper: Option<&MemberRank>, loser: Option<&MemberRank>, keeper_filename: &str, loser_filename: &str) -> String {
+    let separated = keeper.zip(loser).and_then(|(keeper, loser)| {
+        rules.iter().enumerate()
+            .find(|(index, _)| keeper.ranks[*index] != loser.ranks[*index])
+            .map(|(index, rule)| (rule, &keeper.values[index], &loser.values[index]))
+    });
+    match separated {
+        Some((rule, keeper, loser)) if rule.expression.is_some() => format!(
+            "{} (keeper {}, this {})",
+            rule.line, sql_literal(keeper), sql_literal(loser)
+        ),
+        Some((rule, _, _)) => rule.line.clone(),
+        // The deep dive's last resort, which isn't one of the user's rules
+        None if keeper_filename != loser_filename && get_root_filename(loser_filename) == keeper_filename => {
+            "Keeper is the unsuffixed original filename".to_string()
+        }
+        None => "No rule separated them, either could have been kept".to_string(),
+    }
+}
+
+fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Text(text) => format!("'{}'", text),
+        other => display_value(other.clone()),
+    }
+}
+
+fn display_value(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => f.to_string(),
+        Value::Text(t) => t,
+        Value::Blob(b) => format!("<{} bytes>", b.len()),
+    }
+}
+
+/// Every record the user left marked for removal, after flipping keep or remove in the results.
+pub fn marked_records(groups: &[DuplicateGroup]) -> HashSet<FileRecord> {
+    groups.iter()
+        .flat_map(|group| &group.rows)
+        .filter(|row| row.remove)
+        .map(ResultRow::record)
+        .collect()
+}
+
+/// A duplicate group that keeps a different record under the current keeper order than under the saved one.
+#[derive(Clone, Debug)]
+pub struct KeeperChange {
+    pub filename: String,
+    /// Rowid and pathname of the record the saved order keeps.
+    pub before: (usize, String),
+    /// Rowid and pathname of the record the current order keeps.
+    pub after: (usize, String),
+}
+
+/// How switching from a saved keeper order to the current one would change the duplicate filename search.
+#[derive(Clone, Debug, Default)]
+pub struct OrderSimulation {
+    pub groups: usize,
+    pub changed: usize,
+    /// The first changed groups by filename, at most `SIMULATION_SAMPLE` of them.
+    pub sample: Vec<KeeperChange>,
+}
+
+const SIMULATION_SAMPLE: usize = 20;
+
+/// Ranks the duplicate filename groups with both orders without changing anything. Both orders split the
+/// records into the same groups, so a group changes keeper exactly when the saved order's keeper is
+/// marked for removal by the current one.
+pub fn simulate_keeper_order(conn: &mut Connection, saved: &[String], current: &[String], group_by: Option<&str>, group_null: bool, reporter: &Reporter) -> Result<OrderSimulation> {
+    let (partition_by, where_clause) = filename_partition(conn, group_by, group_null)?;
+    reporter.status(Stage::Order, "Ranking duplicates with the saved order");
+    let before = rank_duplicates(conn, &partition_by, TABLE, &where_clause, saved, Stage::Order, reporter)?;
+    reporter.status(Stage::Order, "Ranking duplicates with the current order");
+    let after: HashMap<usize, usize> = rank_duplicates(conn, &partition_by, TABLE, &where_clause, current, Stage::Order, reporter)?
+        .into_iter()
+        .filter_map(|record| Some((record.id, record.keeper?)))
+        .collect();
+
+    let mut keepers: HashMap<usize, String> = HashMap::new();
+    for record in &before {
+        if let Some(keeper) = record.keeper {
+            keepers.entry(keeper).or_insert_with(|| record.filename.clone());
+        }
+    }
+    let mut changes: Vec<(String, usize, usize)> = keepers.iter()
+        .filter_map(|(keeper, filename)| Some((filename.clone(), *keeper, *after.get(keeper)?)))
+        .collect();
+    changes.sort();
+
+    let sample = &changes[..changes.len().min(SIMULATION_SAMPLE)];
+    let ids: Vec<usize> = sample.iter().flat_map(|(_, before, after)| [*before, *after]).collect();
+    let pathnames = record_pathnames(conn, &ids)?;
+    let pathname = |id: usize| (id, pathnames.get(&id).cloned().unwrap_or_default());
+    Ok(OrderSimulation {
+        groups: keepers.len(),
+        changed: changes.len(),
+        sample: sample.iter()
+            .map(|(filename, before, after)| KeeperChange { filename: filename.clone(), before: pathname(*before), after: pathname(*after) })
+            .collect(),
+    })
+}
+
+/// Empty when the database has no pathname column, the changes are still listed by rowid.
+fn record_pathnames(conn: &Connection, ids: &[usize]) -> Result<HashMap<usize, String>> {
+    let mut pathnames = HashMap::new();
+    if !table_columns(conn, "main")?.iter().any(|column| column.eq_ignore_ascii_case("pathname")) {
+        return Ok(pathnames);
+    }
+    for chunk in ids.chunks(BATCH_SIZE) {
+        let placeholders = chunk.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let query = format!("SELECT rowid, COALESCE(pathname, '') FROM {} WHERE rowid IN ({})", TABLE, placeholders);
+        let mut stmt = conn.prepare(&query)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(chunk), |row| Ok((row.get::<_, usize>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (id, pathname) = row?;
+            pathnames.insert(id, pathname);
+        }
+    }
+    Ok(pathnames)
+}
+
+/// Reports its outcome as a status, the returned summary or error is for callers without a UI.
+pub fn remove_duplicates(source_db_path: &str, records: &HashSet<FileRecord>, safe: bool, dupes_db: bool, reporter: &Reporter) -> Result<String, DbError> {
+    if records.is_empty() {
+        let status = "No records marked for removal.".to_string();
+        reporter.status(Stage::Main, status.clone());
+        return Ok(status);
+    }
+    reporter.working(Stage::Main, true);
+
+    let mut created = Vec::new();
+    let result = remove_records(source_db_path, records, safe, dupes_db, &mut created, reporter);
+    match &result {
+        Ok(status) => {
+            reporter.records(Stage::Main, HashSet::new());
+            reporter.status(Stage::Main, status.clone());
+        }
+        Err(e) => {
+            // Nothing was removed, so the databases written for this run are of no use
+            for path in &created {
+                let _ = fs::remove_file(path);
+            }
+            if let DbError::Cancelled = e {
+                reporter.status(Stage::Main, "Removal cancelled. No records were removed.");
+            } else {
+                reporter.status(Stage::Main, format!("Failed to remove records: {}", e));
+            }
+        }
+    }
+    reporter.working(Stage::Main, false);
+    result
+}
+
+fn remove_records(source_db_path: &str, records: &HashSet<FileRecord>, safe: bool, dupes_db: bool, created: &mut Vec<String>, reporter: &Reporter) -> Result<String, DbError> {
+    let mut dupes_message = String::new();
+    if dupes_db {
+        reporter.status(Stage::Main, "Generating Duplicates Only Database");
+        created.push(dupes_db_path(source_db_path));
+        let path = create_duplicates_db(source_db_path, records, reporter)?;
+        dupes_message = format!(" Duplicate records written to {}", path);
+    }
+
+    let mut work_db_path = source_db_path.to_string();
+    if safe {
+        reporter.check_cancelled()?;
+        reporter.status(Stage::Main, format!("Backing up {}", source_db_path));
+        created.push(thinned_db_path(source_db_path));
+        work_db_path = create_safety_db(source_db_path)?;
+    }
+    // Undo looks in the journal of the open database, so a removal from the copy is recorded there too
+    let target = safe.then_some(work_db_path.as_str());
+
+    reporter.check_cancelled()?;
+    reporter.status(Stage::Main, "Removing Records Marked as Duplicates");
+    let mut conn = open_soundminer(&work_db_path)?;
+    reporter.watch(&conn);
+    journal::attach(&conn, source_db_path)?;
+    let deleted = delete_file_records(&mut conn, records, target, reporter);
+    if deleted.is_ok() {
+        // The removal is committed at this point, a failed clean up only costs disk space
+        reporter.status(Stage::Main, "Cleaning up Database");
+        if let Err(e) = vacuum_db(&conn, target) {
+            log::warn!("Failed to vacuum {}: {}", work_db_path, e);
+        }
+    }
+    journal::detach(&conn)?;
+    let deleted = deleted?;
+
+    Ok(if safe {
+        format!("Removed {} records. Thinned database saved to {}.{}", deleted, work_db_path, dupes_message)
+    } else {
+        format!("Removed {} records from {}.{}", deleted, source_db_path, dupes_message)
+    })
+}
+
+/// The file name of a database for display, with either kind of separator on Windows.
+pub fn display_name(path: &str) -> &str {
+    Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or(path)
+}
+
+pub fn thinned_db_path(source_db_path: &str) -> String {
+    format!("{}_thinned.sqlite", source_db_path.trim_end_matches(".sqlite"))
+}
+
+/// Copies the source database to a sibling `_thinned` database and verifies the copy,
+/// so destructive changes never touch the original library.
+pub fn create_safety_db(source_db_path: &str) -> Result<String, DbError> {
+    let work_db_path = thinned_db_path(source_db_path);
+    if Path::new(&work_db_path).exists() {
+        fs::remove_file(&work_db_path)?;
+    }
+    // Undo entries of an earlier thinned copy don't apply to this one
+    journal::clear_target(source_db_path, &work_db_path)?;
+
+    let source_conn = open_soundminer(source_db_path)?;
+    let source_count = get_record_count(&source_conn)?;
+    let mut work_conn = Connection::open(&work_db_path)?;
+    copy_database(&source_conn, &mut work_conn)?;
+
+    let integrity: String = work_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    if integrity != "ok" {
+        return Err(DbError::SafetyCheck(format!("integrity check of {} failed: {}", work_db_path, integrity)));
+    }
+    let work_count = get_record_count(&work_conn)?;
+    if work_count != source_count {
+        return Err(DbError::SafetyCheck(format!(
+            "{} has {} records but {} has {}", work_db_path, work_count, source_db_path, source_count
+        )));
+    }
+    // The records to remove were picked by their rowids in the source, so the copy must hold the same ones
+    work_conn.execute("ATTACH DATABASE ?1 AS source", [source_db_path])?;
+    let differing = differing_rowids(&work_conn);
+    work_conn.execute("DETACH DATABASE source", [])?;
+    match differing? {
+        0 => Ok(work_db_path),
+        differing => Err(DbError::SafetyCheck(format!(
+            "{} rowids of {} differ from {}", differing, work_db_path, source_db_path
+        ))),
+    }
+}
+
+/// Copies every page of `source` with SQLite's backup API, so rowids are exactly those of the source.
+/// A single step reads the whole database under one lock, giving a consistent snapshot even with a pending WAL.
+fn copy_database(source: &Connection, target: &mut Connection) -> Result<(), DbError> {
+    let backup = Backup::new(source, target)?;
+    match backup.step(-1)? {
+        StepResult::Done => Ok(()),
+        _ => Err(DbError::Locked),
+    }
+}
+
+/// Rowids in only one of `main` and the attached `source` database.
+fn differing_rowids(conn: &Connection) -> Result<usize> {
+    conn.query_row(
+        &format!(
+            "SELECT (SELECT COUNT(*) FROM (SELECT rowid FROM main.{0} EXCEPT SELECT rowid FROM source.{0}))
+                  + (SELECT COUNT(*) FROM (SELECT rowid FROM source.{0} EXCEPT SELECT rowid FROM main.{0}))",
+            TABLE
+        ),
+        [],
+        |row| row.get(0),
+    )
+}
+
+fn get_record_count(conn: &Connection) -> Result<usize> {
+    conn.query_row(&format!("SELECT COUNT(*) FROM {}", TABLE), [], |row| row.get(0))
+}
+
+/// Expects the undo journal to be attached, every deleted row is copied there first.
+/// `target` is the path of `conn`'s database when the journal belongs to another one.
+/// Returns how many rows were actually deleted, records already gone don't count.
+pub fn delete_file_records(conn: &mut Connection, records: &HashSet<FileRecord>, target: Option<&str>, reporter: &Reporter) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let description = match target {
+        Some(target) => format!("Remove {} duplicate records from {}", records.len(), target),
+        None => format!("Remove {} duplicate records", records.len()),
+    };
+    let operation = journal::begin(&tx, &description, false, target)?;
+
+    let mut sorted_records: Vec<_> = records.iter().collect();
+    sorted_records.sort_by(|a, b| b.id.cmp(&a.id));
+
+    // Returning early drops the transaction, rolling back every batch already deleted
+    let mut deleted = 0;
+    for (batch, chunk) in sorted_records.chunks(BATCH_SIZE).enumerate() {
+        reporter.check_cancelled()?;
+        reporter.progress(Stage::Main, batch * BATCH_SIZE, records.len());
+        let placeholders = chunk.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let query = format!("DELETE FROM {} WHERE rowid IN ({})", TABLE, placeholders);
+        let ids: Vec<usize> = chunk.iter().map(|record| record.id).collect();
+        journal::record_removed(&tx, operation, &ids)?;
+        deleted += tx.execute(&query, rusqlite::params_from_iter(&ids))?;
+    }
+    reporter.progress(Stage::Main, records.len(), records.len());
+
+    tx.commit()?;
+    Ok(deleted)
+}
+
+pub fn dupes_db_path(source_db_path: &str) -> String {
+    format!("{}_dupes.sqlite", source_db_path.trim_end_matches(".sqlite"))
+}
+
+/// Writes the marked records to a new `_dupes` database. It starts as a full copy of the source, so the
+/// rest of the Soundminer schema comes along and the file opens in Soundminer for review or relinking.
+pub fn create_duplicates_db(source_db_path: &str, records: &HashSet<FileRecord>, reporter: &Reporter) -> Result<String, DbError> {
+    let duplicate_db_path = dupes_db_path(source_db_path);
+    if Path::new(&duplicate_db_path).exists() {
+        fs::remove_file(&duplicate_db_path)?;
+    }
+
+    let source_conn = open_soundminer(source_db_path)?;
+    let mut dupe_conn = Connection::open(&duplicate_db_path)?;
+    copy_database(&source_conn, &mut dupe_conn)?;
+    drop(source_conn);
+    reporter.watch(&dupe_conn);
+
+    let tx = dupe_conn.transaction()?;
+    tx.execute("CREATE TEMP TABLE dupes_keep (id INTEGER PRIMARY KEY)", [])?;
+    {
+        let mut insert = tx.prepare("INSERT INTO temp.dupes_keep (id) VALUES (?1)")?;
+        for (counter, record) in records.iter().enumerate() {
+            if counter % PROGRESS_INTERVAL == 0 {
+                reporter.check_cancelled()?;
+                reporter.progress(Stage::Main, counter, records.len());
+            }
+            insert.execute([record.id])?;
+        }
+    }
+    tx.execute(&format!("DELETE FROM main.{} WHERE rowid NOT IN (SELECT id FROM temp.dupes_keep)", TABLE), [])?;
+    tx.execute("DROP TABLE temp.dupes_keep", [])?;
+    reporter.check_cancelled()?;
+    tx.commit()?;
+    reporter.progress(Stage::Main, records.len(), records.len());
+
+    // Most of the copy was just deleted, and this new file has no undo journal to keep rowids stable for
+    dupe_conn.execute("VACUUM", [])?;
+    Ok(duplicate_db_path)
 }
 
-pub fn remove_duplicates() {}
+/// Expects the undo journal to be attached. VACUUM renumbers the rowids of a table without an
+/// INTEGER PRIMARY KEY but keeps their order, so the records are matched up by position afterwards
+/// and the journal entries of `target`'s operations follow them to their new rowids.
+fn vacuum_db(conn: &Connection, target: Option<&str>) -> Result<()> {
+    conn.execute(&format!(
+        "CREATE TEMP TABLE vacuum_rows AS
+         SELECT rowid AS old_rowid, ROW_NUMBER() OVER (ORDER BY rowid) AS position, filename FROM main.{}",
+        TABLE
+    ), [])?;
+    let vacuumed = conn.execute("VACUUM", []).map(|_| ());
+    let renumbered = vacuumed.and_then(|_| {
+        conn.execute_batch(&format!(
+            "CREATE TEMP TABLE vacuum_map AS
+             SELECT v.old_rowid, t.rowid AS new_rowid, v.filename IS t.filename AS same
+             FROM temp.vacuum_rows v
+             LEFT JOIN (SELECT rowid, ROW_NUMBER() OVER (ORDER BY rowid) AS position, filename FROM main.{}) t
+             ON t.position = v.position;",
+            TABLE
+        ))?;
+        let matched: bool = conn.query_row(
+            "SELECT COUNT(*) = 0 FROM temp.vacuum_map WHERE new_rowid IS NULL OR NOT same",
+            [],
+            |row| row.get(0),
+        )?;
+        let tx = conn.unchecked_transaction()?;
+        if matched {
+            journal::renumber(&tx, target)?;
+        } else {
+            log::warn!("Records moved during VACUUM, their undo entries are discarded");
+            journal::discard(&tx, target)?;
+        }
+        tx.commit()
+    });
+    conn.execute_batch("DROP TABLE IF EXISTS temp.vacuum_rows; DROP TABLE IF EXISTS temp.vacuum_map;")?;
+    renumbered
+}
 
-pub fn open_db() -> Option<String> {
-    if let Some(path) = rfd::FileDialog::new().pick_file() {
-        let db_path = path.display().to_string();
-        if db_path.ends_with(".sqlite") {return Some(db_path);}
-    }    
-    None
+/// Opens an existing Soundminer database, so a file that isn't one is refused up front
+/// instead of failing halfway through a job. Missing files are not created.
+pub fn open_soundminer(db_path: &str) -> Result<Connection, DbError> {
+    if !Path::new(db_path).is_file() {
+        return Err(DbError::Io(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", db_path))));
+    }
+    let conn = Connection::open(db_path)?;
+    let has_table: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [TABLE],
+        |row| row.get(0),
+    )?;
+    if !has_table {
+        return Err(DbError::NotSoundminer);
+    }
+    Ok(conn)
 }
 
-pub fn get_db_size(db_path: String) -> usize {
-    let conn = Connection::open(db_path).unwrap();
-     let count: usize = conn.query_row(
-         "SELECT COUNT(*) FROM justinmetadata",
-         [],
-         |row| row.get(0) 
-     ).unwrap();
-     count
+pub fn get_db_size(db_path: &str) -> Result<usize, DbError> {
+    let conn = open_soundminer(db_path)?;
+    Ok(get_record_count(&conn)?)
 }
 
-pub fn get_columns(db_path: String) -> Vec<String> {
-    let conn = Connection::open(db_path).unwrap();
-    let mut stmt = conn.prepare("PRAGMA table_info(justinmetadata);").unwrap();
+pub fn get_columns(db_path: &str) -> Result<Vec<String>, DbError> {
+    let conn = open_soundminer(db_path)?;
+    Ok(editable_columns(&conn)?)
+}
 
-    // Execute the query and collect the column names into a Vec<String>
-    let mut column_names: Vec<String> = stmt.query_map([], |row| {
-        Ok(row.get::<_, String>(1)?) // The 1st index corresponds to the "name" column
-    }).unwrap()
-    .filter_map(Result::ok) // Filter out any errors
-    .filter(|c| !c.starts_with("_"))
-    .collect();
+/// Compiles `SELECT rowid FROM justinmetadata ORDER BY <line>` for each keeper order line without running it,
+/// so a typo shows up when the line is entered instead of when a search fails. Gives SQLite's message for
+/// each invalid line and `None` for the valid ones.
+pub fn check_order(db_path: &str, lines: &[String]) -> Result<Vec<Option<String>>, DbError> {
+    let conn = open_soundminer(db_path)?;
+    Ok(lines.iter().map(|line| check_order_line(&conn, line).err()).collect())
+}
 
+/// Fails on the first line of `order` that can't rank this database, such as one naming a missing column.
+/// Blank lines are skipped, as they are when ranking.
+pub fn require_order(conn: &Connection, order: &[String]) -> Result<(), DbError> {
+    for line in order.iter().filter(|line| !line.trim().is_empty()) {
+        check_order_line(conn, line).map_err(|message| DbError::InvalidOrder(line.trim().to_string(), message))?;
+    }
+    Ok(())
+}
 
-    column_names.sort();
-    column_names
+/// The characters of `line` outside quoted strings and names, each with how deeply it sits in parentheses.
+/// A quoted part shows only as its opening quote, so the characters around it don't run together.
+fn unquoted_chars(line: &str) -> Vec<(char, usize)> {
+    let mut unquoted = Vec::new();
+    let mut quote = None;
+    let mut depth = 0usize;
+    for c in line.chars() {
+        match (quote, c) {
+            (None, '\'' | '"' | '`') => {
+                unquoted.push((c, depth));
+                quote = Some(c);
+            }
+            (None, '[') => {
+                unquoted.push((c, depth));
+                quote = Some(']');
+            }
+            (Some(close), _) if c == close => quote = None,
+            (Some(_), _) => {}
+            (None, '(') => {
+                unquoted.push((c, depth));
+                depth += 1;
+            }
+            (None, ')') => {
+                depth = depth.saturating_sub(1);
+                unquoted.push((c, depth));
+            }
+            (None, _) => unquoted.push((c, depth)),
+        }
+    }
+    unquoted
+}
+
+/// Whether the line has a `;` outside a quoted string or name. SQLite would compile the
+/// statement before it and ignore the rest.
+fn ends_statement(line: &str) -> bool {
+    unquoted_chars(line).iter().any(|&(c, _)| c == ';')
+}
+
+/// Whether the line sorts by more than one term, like `a ASC, b DESC`.
+fn has_top_level_comma(line: &str) -> bool {
+    unquoted_chars(line).iter().any(|&(c, depth)| c == ',' && depth == 0)
+}
+
+/// Whether a `--` or `/*` comment starts outside quotes, it would hide the rest of the ranking query.
+fn has_comment(line: &str) -> bool {
+    unquoted_chars(line).windows(2).any(|pair| matches!((pair[0].0, pair[1].0), ('-', '-') | ('/', '*')))
+}
+
+fn check_order_line(conn: &Connection, line: &str) -> Result<(), String> {
+    if line.trim().is_empty() {
+        return Err("Empty line".to_string());
+    }
+    // The line is pasted into the ranking query, so it must not end the statement early
+    if ends_statement(line) {
+        return Err("A line can't contain ';' outside of quotes".to_string());
+    }
+    if has_comment(line) {
+        return Err("A line can't contain a -- or /* comment".to_string());
+    }
+    match conn.prepare(&format!("SELECT rowid FROM {} ORDER BY {}", TABLE, line)) {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::SqliteFailure(_, Some(message))) => Err(message),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 pub fn default_tags() -> Vec<String> {
@@ -321,4 +1514,348 @@ const TJF_ORDER_VEC: [&str; 22] = [
     "scannedDate ASC",
 ];
     TJF_ORDER_VEC.map(|s| s.to_string()).to_vec()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    /// A reporter whose messages nobody reads.
+    pub(crate) fn reporter() -> Reporter {
+        let (sender, receiver) = channel();
+        std::mem::forget(receiver);
+        Reporter::new(sender)
+    }
+
+    const LIBRARY_TABLE: &str = "CREATE TABLE justinmetadata (filename TEXT, pathname TEXT, duration TEXT, Description TEXT, Show TEXT, _Dirty INTEGER)";
+
+    /// Adds `(filename, pathname, Description, Show)` rows, in rowid order.
+    pub(crate) fn insert_records(conn: &Connection, rows: &[(&str, &str, &str, &str)]) {
+        for (filename, pathname, description, show) in rows {
+            conn.execute(
+                "INSERT INTO justinmetadata (filename, pathname, duration, Description, Show) VALUES (?1, ?2, '00:00:01.000', ?3, ?4)",
+                [filename, pathname, description, show],
+            ).unwrap();
+        }
+    }
+
+    /// A fresh Soundminer database file in its own directory, for code that opens databases by path.
+    /// The table has no INTEGER PRIMARY KEY, like Soundminer's, so VACUUM renumbers its rowids.
+    pub(crate) fn library(name: &str, rows: &[(&str, &str, &str, &str)]) -> String {
+        let dir = std::env::temp_dir().join("smdb_companion_tests").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("library.sqlite").display().to_string();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(LIBRARY_TABLE, []).unwrap();
+        insert_records(&conn, rows);
+        path
+    }
+
+    fn memory_library(rows: &[(&str, &str, &str, &str)]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(LIBRARY_TABLE, []).unwrap();
+        insert_records(&conn, rows);
+        conn
+    }
+
+    /// `(rowid, keeper)` of each marked record, sorted.
+    fn marked(records: &HashSet<FileRecord>) -> Vec<(usize, Option<usize>)> {
+        let mut marked: Vec<_> = records.iter().map(|record| (record.id, record.keeper)).collect();
+        marked.sort();
+        marked
+    }
+
+    fn order(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn rank_duplicates_keeps_the_first_record_of_the_order() {
+        let mut conn = memory_library(&[
+            ("door.wav", "/b/door.wav", "", ""),
+            ("door.wav", "/a/door.wav", "", ""),
+            ("door.wav", "/c/door.wav", "slam", ""),
+            ("gate.wav", "/a/gate.wav", "", ""),
+        ]);
+        let by_pathname = gather_duplicate_filenames_in_database(&mut conn, &order(&["pathname ASC"]), None, false, &reporter()).unwrap();
+        assert_eq!(marked(&by_pathname), vec![(1, Some(2)), (3, Some(2))]);
+
+        let described = order(&["CASE WHEN Description != '' THEN 0 ELSE 1 END ASC", "pathname ASC"]);
+        let by_description = gather_duplicate_filenames_in_database(&mut conn, &described, None, false, &reporter()).unwrap();
+        assert_eq!(marked(&by_description), vec![(1, Some(3)), (2, Some(3))]);
+    }
+
+    #[test]
+    fn rank_duplicates_falls_back_to_rowid_order() {
+        let mut conn = memory_library(&[
+            ("door.wav", "/b/door.wav", "", ""),
+            ("door.wav", "/a/door.wav", "", ""),
+        ]);
+        let records = gather_duplicate_filenames_in_database(&mut conn, &order(&["", "  "]), None, false, &reporter()).unwrap();
+        assert_eq!(marked(&records), vec![(2, Some(1))]);
+    }
+
+    #[test]
+    fn rank_duplicates_searches_each_group_on_its_own() {
+        let mut conn = memory_library(&[
+            ("door.wav", "/a/door.wav", "", "Show A"),
+            ("door.wav", "/b/door.wav", "", "Show B"),
+            ("door.wav", "/c/door.wav", "", "Show A"),
+            ("door.wav", "/d/door.wav", "", ""),
+            ("door.wav", "/e/door.wav", "", ""),
+        ]);
+        let pathname = order(&["pathname ASC"]);
+        let skipped = gather_duplicate_filenames_in_database(&mut conn, &pathname, Some("Show"), false, &reporter()).unwrap();
+        assert_eq!(marked(&skipped), vec![(3, Some(1))]);
+        let together = gather_duplicate_filenames_in_database(&mut conn, &pathname, Some("Show"), true, &reporter()).unwrap();
+        assert_eq!(marked(&together), vec![(3, Some(1)), (5, Some(4))]);
+    }
+
+    #[test]
+    fn deep_dive_ranks_suffixed_copies_against_the_original() {
+        let mut conn = memory_library(&[
+            ("door.1.wav", "/a/door.1.wav", "", ""),
+            ("door.wav", "/b/door.wav", "", ""),
+            ("door.M.wav", "/c/door.M.wav", "", ""),
+        ]);
+        let records = gather_records_with_trailing_numbers(&mut conn, &order(&["pathname ASC"]), &reporter()).unwrap();
+        assert_eq!(records.iter().map(|record| record.id).collect::<HashSet<_>>(), HashSet::from([2, 3]));
+    }
+
+    #[test]
+    fn deep_dive_accepts_rowid_in_the_order() {
+        let rows = [("door.1.wav", "/a/door.1.wav", "", ""), ("door.wav", "/b/door.wav", "", "")];
+        let rowid = order(&["rowid DESC"]);
+        let mut conn = memory_library(&rows);
+        require_order(&conn, &rowid).unwrap();
+        let records = gather_records_with_trailing_numbers(&mut conn, &rowid, &reporter()).unwrap();
+        assert_eq!(marked(&records), vec![(1, Some(2))]);
+        let records = gather_records_with_trailing_numbers(&mut conn, &order(&["rowid ASC"]), &reporter()).unwrap();
+        assert_eq!(marked(&records), vec![(2, Some(1))]);
+    }
+
+    #[test]
+    fn cancelled_search_returns_an_interrupt() {
+        let mut conn = memory_library(&[("door.wav", "/a/door.wav", "", ""), ("door.wav", "/b/door.wav", "", "")]);
+        let reporter = reporter();
+        reporter.cancel().request();
+        let result = gather_duplicate_filenames_in_database(&mut conn, &[], None, false, &reporter);
+        assert!(matches!(result.map_err(DbError::from), Err(DbError::Cancelled)));
+    }
+
+    fn reasons(path: &str, order: &[String]) -> Vec<(usize, bool, String)> {
+        let records = HashSet::from([FileRecord { id: 1, filename: "door.wav".to_string(), duration: String::new(), keeper: Some(2) }]);
+        let groups = load_duplicate_groups(path, &records, order, &reporter()).unwrap();
+        groups[0].rows.iter().map(|row| (row.id, row.keeper, row.reason.clone())).collect()
+    }
+
+    #[test]
+    fn groups_explain_ranks_the_way_sqlite_sorts() {
+        let path = library("group_reasons", &[("door.wav", "/b/door.wav", "SLAM", ""), ("door.wav", "/a/door.wav", "slam", "")]);
+        // Under NOCASE the descriptions tie, so the pathname is what ranked them
+        let nocase = reasons(&path, &order(&["Description COLLATE NOCASE ASC", "pathname ASC"]));
+        assert_eq!(nocase, vec![
+            (2, true, String::new()),
+            (1, false, "pathname ASC (keeper '/a/door.wav', this '/b/door.wav')".to_string()),
+        ]);
+        let several = reasons(&path, &order(&["Description COLLATE NOCASE, pathname ASC"]));
+        assert_eq!(several[1].2, "Description COLLATE NOCASE, pathname ASC");
+        let rowid = reasons(&path, &order(&["rowid DESC"]));
+        assert_eq!(rowid[1].2, "rowid DESC (keeper 2, this 1)");
+        let tied = reasons(&path, &order(&["duration ASC"]));
+        assert_eq!(tied[1].2, "No rule separated them, either could have been kept");
+    }
+
+    #[test]
+    fn safe_removal_leaves_the_source_alone() {
+        let path = library("safe_removal", &[
+            ("door.wav", "/a/door.wav", "", ""),
+            ("door.wav", "/b/door.wav", "", ""),
+            ("gate.wav", "/a/gate.wav", "", ""),
+        ]);
+        let mut conn = open_soundminer(&path).unwrap();
+        let records = gather_duplicate_filenames_in_database(&mut conn, &order(&["pathname ASC"]), None, false, &reporter()).unwrap();
+        drop(conn);
+
+        let summary = remove_duplicates(&path, &records, true, true, &reporter()).unwrap();
+        assert!(summary.contains(&thinned_db_path(&path)), "{}", summary);
+        assert_eq!(get_record_count(&open_soundminer(&path).unwrap()).unwrap(), 3);
+        let thinned = open_soundminer(&thinned_db_path(&path)).unwrap();
+        let pathnames: Vec<String> = thinned.prepare("SELECT pathname FROM justinmetadata ORDER BY rowid").unwrap()
+            .query_map([], |row| row.get(0)).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(pathnames, vec!["/a/door.wav", "/a/gate.wav"]);
+        let dupes = open_soundminer(&dupes_db_path(&path)).unwrap();
+        let pathname: String = dupes.query_row("SELECT pathname FROM justinmetadata", [], |row| row.get(0)).unwrap();
+        assert_eq!(pathname, "/b/door.wav");
+    }
+
+    #[test]
+    fn compare_needs_another_existing_database() {
+        let path = library("compare_target", &[("door.wav", "/a/door.wav", "", ""), ("gate.wav", "/a/gate.wav", "", "")]);
+        let other = library("compare_other", &[("gate.wav", "/b/gate.wav", "", "")]);
+        let conn = open_soundminer(&path).unwrap();
+        let records = gather_compare_database_overlaps(&conn, &other, &[], &reporter()).unwrap();
+        assert_eq!(marked(&records), vec![(2, None)]);
+
+        let missing = format!("{}.missing", other);
+        assert!(matches!(gather_compare_database_overlaps(&conn, &missing, &[], &reporter()), Err(DbError::Io(_))));
+        assert!(!Path::new(&missing).exists());
+        assert!(matches!(gather_compare_database_overlaps(&conn, &path, &[], &reporter()), Err(DbError::CompareWithItself)));
+    }
+
+    #[test]
+    fn removal_counts_only_the_rows_deleted() {
+        let path = library("removal_count", &[("door.wav", "/a/door.wav", "", ""), ("door.wav", "/b/door.wav", "", "")]);
+        let record = |id| FileRecord { id, filename: "door.wav".to_string(), duration: String::new(), keeper: Some(1) };
+        // Rowid 5 was never there, say it went in another run
+        let records = HashSet::from([record(2), record(5)]);
+        let summary = remove_duplicates(&path, &records, false, false, &reporter()).unwrap();
+        assert!(summary.starts_with("Removed 1 records from"), "{}", summary);
+    }
+
+    #[test]
+    fn cancelled_removal_rolls_back() {
+        let path = library("cancelled_removal", &[("door.wav", "/a/door.wav", "", ""), ("door.wav", "/b/door.wav", "", "")]);
+        let records = HashSet::from([FileRecord { id: 2, filename: "door.wav".to_string(), duration: String::new(), keeper: Some(1) }]);
+        let reporter = reporter();
+        reporter.cancel().request();
+        assert!(matches!(remove_duplicates(&path, &records, false, false, &reporter), Err(DbError::Cancelled)));
+        assert_eq!(get_record_count(&open_soundminer(&path).unwrap()).unwrap(), 2);
+    }
+
+    #[test]
+    fn root_filename_strips_suffixes() {
+        assert_eq!(get_root_filename("door.1.wav"), "door.wav");
+        assert_eq!(get_root_filename("door.1.2.3.wav"), "door.wav");
+        assert_eq!(get_root_filename("door.M.wav"), "door.wav");
+        assert_eq!(get_root_filename("door.2.M.aif"), "door.aif");
+    }
+
+    #[test]
+    fn display_name_is_the_file_name() {
+        assert_eq!(display_name("/Volumes/Libraries/library.sqlite"), "library.sqlite");
+        assert_eq!(display_name("library.sqlite"), "library.sqlite");
+        assert_eq!(display_name("/"), "/");
+        if cfg!(windows) {
+            assert_eq!(display_name(r"C:\Libraries\library.sqlite"), "library.sqlite");
+        }
+    }
+
+    #[test]
+    fn order_rule_splits_off_the_direction() {
+        let rule = OrderRule::parse(" CASE WHEN x THEN 0 ELSE 1 END  desc nulls FIRST ");
+        assert_eq!(rule.line, "CASE WHEN x THEN 0 ELSE 1 END  desc nulls FIRST");
+        assert_eq!(rule.expression.as_deref(), Some("CASE WHEN x THEN 0 ELSE 1 END"));
+        assert_eq!(OrderRule::parse("duration").expression.as_deref(), Some("duration"));
+        assert_eq!(OrderRule::parse("coalesce(a, b) ASC").expression.as_deref(), Some("coalesce(a, b)"));
+        assert_eq!(OrderRule::parse("a ASC, b DESC").expression, None);
+        assert_eq!(OrderRule::parse("a = ',' DESC").expression.as_deref(), Some("a = ','"));
+    }
+
+    #[test]
+    fn order_lines_are_checked_against_the_database() {
+        let conn = memory_library(&[]);
+        assert_eq!(check_order_line(&conn, "pathname ASC"), Ok(()));
+        assert_eq!(check_order_line(&conn, "CASE WHEN pathname LIKE '%--%' OR pathname LIKE '%/*%' THEN 0 ELSE 1 END ASC"), Ok(()));
+        assert_eq!(check_order_line(&conn, "coalesce(Show, Description, '') DESC"), Ok(()));
+        assert_eq!(check_order_line(&conn, "[a,b] ASC").unwrap_err(), "no such column: a,b");
+        assert_eq!(check_order_line(&conn, "  ").unwrap_err(), "Empty line");
+        assert_eq!(check_order_line(&conn, "bogus ASC").unwrap_err(), "no such column: bogus");
+    }
+
+    #[test]
+    fn order_lines_refuse_extra_statements_and_comments() {
+        let conn = memory_library(&[]);
+        for line in ["pathname ASC; DROP TABLE justinmetadata", "pathname ASC -- keep the library", "pathname /* keep */ ASC"] {
+            assert!(check_order_line(&conn, line).is_err(), "{}", line);
+        }
+        assert_eq!(check_order_line(&conn, "pathname ASC, duration DESC"), Ok(()));
+        assert!(!has_comment("pathname - -1 ASC"));
+        assert!(!has_comment("pathname -'-' ASC"));
+    }
+
+    #[test]
+    fn root_filename_keeps_names_without_suffix() {
+        assert_eq!(get_root_filename("door.wav"), "door.wav");
+        assert_eq!(get_root_filename("door 1.wav"), "door 1.wav");
+        assert_eq!(get_root_filename("door.Mix.wav"), "door.Mix.wav");
+        assert_eq!(get_root_filename(".1.wav"), ".1.wav");
+        assert_eq!(get_root_filename("door"), "door");
+    }
+
+    fn replaced(find: &str, use_regex: bool, match_case: bool, whole_word: bool, value: &str, replace: &str) -> String {
+        Matcher::new(find, use_regex, match_case, whole_word).unwrap().replace(value, replace).into_owned()
+    }
+
+    #[test]
+    fn whole_word_matches_text_with_non_word_ends() {
+        assert_eq!(replaced("-6030_", false, true, true, "door-6030_ slam", ""), "door slam");
+        assert_eq!(replaced("-6030_", false, true, true, "-6030_door", "+"), "-6030_door");
+        assert_eq!(replaced("(old)", false, true, true, "take (old) 2", "(new)"), "take (new) 2");
+    }
+
+    #[test]
+    fn whole_word_skips_matches_inside_words() {
+        assert_eq!(replaced("door", false, false, true, "Door doorbell door", "gate"), "gate doorbell gate");
+        assert!(matches!(Matcher::new("door", false, true, true).unwrap().replace("doorbell", "gate"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn regex_mode_expands_captures() {
+        assert_eq!(replaced(r"(\w+)_(\d+)", true, true, false, "door_01", "$2 $1"), "01 door");
+        assert_eq!(replaced(r"(\w+)_(\d+)", true, true, true, "door_01 x", "$2"), "01 x");
+        assert_eq!(replaced("$1", false, true, false, "cost $1", "$2"), "cost $2");
+    }
+
+    fn records(text: &str) -> Vec<(usize, Vec<String>)> {
+        parse_csv_records(text).unwrap()
+    }
+
+    fn fields(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn csv_quoted_fields_hold_commas_quotes_and_line_breaks() {
+        assert_eq!(records("a,\"b, c\",d"), vec![(1, fields(&["a", "b, c", "d"]))]);
+        assert_eq!(records("a,\"say \"\"hi\"\"\",\"\"\"\""), vec![(1, fields(&["a", "say \"hi\"", "\""]))]);
+        assert_eq!(records("a,\"two\nlines\",b\nc,d,e"), vec![(1, fields(&["a", "two\nlines", "b"])), (3, fields(&["c", "d", "e"]))]);
+        assert_eq!(records("a,,\"\""), vec![(1, fields(&["a", "", ""]))]);
+    }
+
+    #[test]
+    fn csv_accepts_crlf_and_a_byte_order_mark() {
+        assert_eq!(
+            records("\u{feff}a,b\r\nc,\"d\r\ne\"\r\n"),
+            vec![(1, fields(&["a", "b"])), (2, fields(&["c", "d\r\ne"]))]
+        );
+        assert_eq!(records("a,b\r\n"), records("a,b\n"));
+    }
+
+    #[test]
+    fn csv_reports_an_unclosed_quote_with_its_line() {
+        assert_eq!(parse_csv_records("a,b\nc,\"d\ne"), Err("Line 2: quoted field is never closed".to_string()));
+    }
+
+    #[test]
+    fn batch_csv_reads_rules_after_a_header() {
+        let rules = parse_batch_csv("column,find,replace,regex\r\n\r\nfilename, b.,B.\n\"Description\",\"dog, \"\"big\"\"\",cat,TRUE\n").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!((rules[0].line, rules[0].column.as_str(), rules[0].find.as_str(), rules[0].regex), (3, "filename", " b.", false));
+        assert_eq!((rules[1].line, rules[1].find.as_str(), rules[1].replace.as_str(), rules[1].regex), (4, "dog, \"big\"", "cat", true));
+    }
+
+    #[test]
+    fn batch_csv_rejects_bad_rows() {
+        assert_eq!(parse_batch_csv("filename,a,b\nfilename,a").unwrap_err(), "Line 2: expected column,find,replace[,regex] but found 2 fields");
+        assert!(parse_batch_csv("filename,a,b,true,extra").is_err());
+        assert_eq!(parse_batch_csv("filename,a,b,maybe").unwrap_err(), "Line 1: 'maybe' is not true or false");
+        assert_eq!(parse_batch_csv("filename,,b").unwrap_err(), "Line 1: find text is empty");
+        assert!(parse_batch_csv("filename,\"a,b").is_err());
+        // Only the first line can be a header, later it is a rule like any other
+        assert_eq!(parse_batch_csv("filename,a,b\ncolumn,find,replace").unwrap()[1].column, "column");
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{ffi, Connection, InterruptHandle};
+use crate::processing::{DuplicateGroup, FileRecord, OrderSimulation, ReplaceRow};
+
+/// Which part of the UI a message belongs to, each maps onto one of the app's `Config`s.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage { Main, Group, Deep, Tags, Compare, Find, Batch, Undo, Order }
+
+impl Stage {
+    pub const ALL: [Stage; 9] = [Stage::Main, Stage::Group, Stage::Deep, Stage::Tags, Stage::Compare, Stage::Find, Stage::Batch, Stage::Undo, Stage::Order];
+}
+
+pub enum Message {
+    Status(Stage, String),
+    Working(Stage, bool),
+    Progress(Stage, f32),
+    Records(Stage, HashSet<FileRecord>),
+    Results(Vec<DuplicateGroup>),
+    Simulation(OrderSimulation),
+    Count(usize),
+    Preview(Vec<ReplaceRow>),
+    BatchCounts(Vec<usize>),
+    Done,
+}
+
+/// Shared between a `Reporter` and whoever may cancel its job.
+#[derive(Default)]
+pub(crate) struct Cancel {
+    requested: AtomicBool,
+    interrupt: Mutex<Option<InterruptHandle>>,
+}
+
+impl Cancel {
+    /// Raises the flag `check_cancelled` looks at and interrupts the watched statement.
+    pub(crate) fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+        if let Ok(interrupt) = self.interrupt.lock() {
+            if let Some(interrupt) = interrupt.as_ref() {
+                interrupt.interrupt();
+            }
+        }
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+}
+
+/// Handed to background jobs so they can stream their progress back to whoever started them.
+pub struct Reporter {
+    sender: Sender<Message>,
+    on_send: Option<Box<dyn Fn() + Send>>,
+    cancel: Arc<Cancel>,
+}
+
+impl Reporter {
+    pub fn new(sender: Sender<Message>) -> Self {
+        Self { sender, on_send: None, cancel: Arc::default() }
+    }
+    /// Calls `on_send` after each message, for a UI that has to be woken up to read it.
+    pub fn on_send(mut self, on_send: impl Fn() + Send + 'static) -> Self {
+        self.on_send = Some(Box::new(on_send));
+        self
+    }
+    pub(crate) fn cancel(&self) -> Arc<Cancel> {
+        self.cancel.clone()
+    }
+    pub fn send(&self, message: Message) {
+        // The receiver is gone if the app closed mid job, there is nobody left to tell
+        let _ = self.sender.send(message);
+        if let Some(on_send) = &self.on_send {
+            on_send();
+        }
+    }
+    pub fn status(&self, stage: Stage, status: impl Into<String>) {
+        self.send(Message::Status(stage, status.into()));
+    }
+    pub fn working(&self, stage: Stage, working: bool) {
+        self.send(Message::Working(stage, working));
+    }
+    pub fn progress(&self, stage: Stage, done: usize, total: usize) {
+        let progress = if total == 0 { 1.0 } else { done as f32 / total as f32 };
+        self.send(Message::Progress(stage, progress.min(1.0)));
+    }
+    pub fn records(&self, stage: Stage, records: HashSet<FileRecord>) {
+        self.send(Message::Records(stage, records));
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_requested()
+    }
+    /// Call between SQL batches, returns an interrupt error once the user has cancelled the job.
+    pub fn check_cancelled(&self) -> rusqlite::Result<()> {
+        if self.is_cancelled() { Err(cancelled_error()) } else { Ok(()) }
+    }
+    /// Lets a cancel interrupt whatever statement is running on `conn` instead of waiting for it.
+    pub fn watch(&self, conn: &Connection) {
+        if let Ok(mut interrupt) = self.cancel.interrupt.lock() {
+            *interrupt = Some(conn.get_interrupt_handle());
+        }
+    }
+}
+
+/// Surfaces as `DbError::Cancelled` once it reaches the processing layer's entry points.
+pub fn cancelled_error() -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_INTERRUPT), Some("Cancelled by user".to_string()))
+}
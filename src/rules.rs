@@ -0,0 +1,250 @@
+use std::sync::OnceLock;
+
+use regex::{Regex, RegexBuilder};
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Operator { #[default] Contains, NotContains, IsEmpty, Greater, Less }
+
+impl Operator {
+    pub const ALL: [Operator; 5] = [Operator::Contains, Operator::NotContains, Operator::IsEmpty, Operator::Greater, Operator::Less];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operator::Contains => "contains",
+            Operator::NotContains => "does not contain",
+            Operator::IsEmpty => "is empty",
+            Operator::Greater => "greater",
+            Operator::Less => "less",
+        }
+    }
+
+    pub fn takes_value(self) -> bool {
+        self != Operator::IsEmpty
+    }
+}
+
+/// One line of the keeper priority order in a form that can be edited without writing SQL.
+/// It compiles to the same ORDER BY terms the built in orders are written in, and lines in
+/// that form parse back, anything else stays a raw SQL line.
+#[derive(Clone, PartialEq, Debug)]
+pub struct KeeperRule {
+    pub column: String,
+    pub operator: Operator,
+    /// Text to look for, LIKE wildcards `%` and `_` included. Greater and less compare against it when set,
+    /// and sort by the column itself when it's empty.
+    pub value: String,
+    /// Keep records matching the rule over those that don't, or the reverse.
+    pub prefer: bool,
+}
+
+impl Default for KeeperRule {
+    fn default() -> Self {
+        Self { column: "pathname".to_string(), operator: Operator::Contains, value: String::new(), prefer: true }
+    }
+}
+
+impl KeeperRule {
+    pub fn compile(&self) -> String {
+        let column = quote_column(&self.column);
+        // Records scoring 0 sort first and are kept
+        let (matched, other) = if self.prefer { (0, 1) } else { (1, 0) };
+        match self.operator {
+            Operator::Contains => format!("CASE WHEN {} LIKE '%{}%' THEN {} ELSE {} END ASC", column, escape(&self.value), matched, other),
+            Operator::NotContains => format!("CASE WHEN {} NOT LIKE '%{}%' THEN {} ELSE {} END ASC", column, escape(&self.value), matched, other),
+            // Written as "has a value" so the Description rule in the built in orders reads the same,
+            // which flips the scores
+            Operator::IsEmpty => format!("CASE WHEN {} IS NOT NULL AND {} != '' THEN {} ELSE {} END ASC", column, column, other, matched),
+            Operator::Greater | Operator::Less if self.value.trim().is_empty() => {
+                let descending = (self.operator == Operator::Greater) == self.prefer;
+                format!("{} {}", column, if descending { "DESC" } else { "ASC" })
+            }
+            Operator::Greater | Operator::Less => format!(
+                "CASE WHEN {} {} {} THEN {} ELSE {} END ASC",
+                column, if self.operator == Operator::Greater { ">" } else { "<" }, literal(self.value.trim()), matched, other
+            ),
+        }
+    }
+
+    /// Reads a line written by `compile`, or `None` for SQL the builder can't show.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let (like, empty, compare, sort) = patterns();
+        if let Some(caps) = like.captures(line) {
+            let operator = if caps.name("not").is_some() { Operator::NotContains } else { Operator::Contains };
+            return Some(Self { column: unquote_column(&caps["column"]), operator, value: caps["value"].replace("''", "'"), prefer: prefers(&caps["matched"], &caps["other"])? });
+        }
+        if let Some(caps) = empty.captures(line) {
+            if unquote_column(&caps["column"]) != unquote_column(&caps["again"]) {
+                return None;
+            }
+            // The scores are those of records that have a value
+            return Some(Self { column: unquote_column(&caps["column"]), operator: Operator::IsEmpty, value: String::new(), prefer: prefers(&caps["other"], &caps["matched"])? });
+        }
+        if let Some(caps) = compare.captures(line) {
+            let operator = if &caps["op"] == ">" { Operator::Greater } else { Operator::Less };
+            let value = match caps["value"].strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+                Some(text) => text.replace("''", "'"),
+                None => caps["value"].to_string(),
+            };
+            return Some(Self { column: unquote_column(&caps["column"]), operator, value, prefer: prefers(&caps["matched"], &caps["other"])? });
+        }
+        if let Some(caps) = sort.captures(line) {
+            let descending = caps["direction"].eq_ignore_ascii_case("DESC");
+            return Some(Self {
+                column: unquote_column(&caps["column"]),
+                operator: if descending { Operator::Greater } else { Operator::Less },
+                value: String::new(),
+                prefer: true,
+            });
+        }
+        None
+    }
+
+    /// The rule in words, for the order list.
+    pub fn describe(&self) -> String {
+        let verb = if self.prefer { "Prefer" } else { "Avoid" };
+        match self.operator {
+            Operator::Contains => format!("{} {} containing '{}'", verb, self.column, self.value),
+            Operator::NotContains => format!("{} {} not containing '{}'", verb, self.column, self.value),
+            Operator::IsEmpty => format!("{} empty {}", verb, self.column),
+            Operator::Greater if self.value.trim().is_empty() => format!("{} greatest {}", verb, self.column),
+            Operator::Less if self.value.trim().is_empty() => format!("{} least {}", verb, self.column),
+            Operator::Greater => format!("{} {} greater than {}", verb, self.column, self.value),
+            Operator::Less => format!("{} {} less than {}", verb, self.column, self.value),
+        }
+    }
+}
+
+fn patterns() -> &'static (Regex, Regex, Regex, Regex) {
+    const COLUMN: &str = r#"(\w+|"[^"]+")"#;
+    const SCORES: &str = r"THEN\s+(?P<matched>[01])\s+ELSE\s+(?P<other>[01])\s+END\s+ASC";
+    static PATTERNS: OnceLock<(Regex, Regex, Regex, Regex)> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        let build = |pattern: String| RegexBuilder::new(&pattern).case_insensitive(true).build()
+            .expect("keeper rule patterns are built from valid fragments");
+        (
+            build(format!(r"^CASE\s+WHEN\s+(?P<column>{})\s+(?P<not>NOT\s+)?LIKE\s+'%(?P<value>(?:[^']|'')*)%'\s+{}$", COLUMN, SCORES)),
+            build(format!(r"^CASE\s+WHEN\s+(?P<column>{})\s+IS\s+NOT\s+NULL\s+AND\s+(?P<again>{})\s*!=\s*''\s+{}$", COLUMN, COLUMN, SCORES)),
+            build(format!(r"^CASE\s+WHEN\s+(?P<column>{})\s*(?P<op>[<>])\s*(?P<value>'(?:[^']|'')*'|-?[0-9]+(?:\.[0-9]+)?)\s+{}$", COLUMN, SCORES)),
+            build(format!(r"^(?P<column>{})\s+(?P<direction>ASC|DESC)$", COLUMN)),
+        )
+    })
+}
+
+/// Scores of 0 for matching records and 1 for the rest keep the matches, anything but a swap of the two isn't a rule.
+fn prefers(matched: &str, other: &str) -> Option<bool> {
+    match (matched, other) {
+        ("0", "1") => Some(true),
+        ("1", "0") => Some(false),
+        _ => None,
+    }
+}
+
+fn quote_column(column: &str) -> String {
+    if !column.is_empty() && column.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        column.to_string()
+    } else {
+        format!("\"{}\"", column.replace('"', ""))
+    }
+}
+
+fn unquote_column(column: &str) -> String {
+    column.trim_matches('"').to_string()
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\'', "''")
+}
+
+/// Plain decimal numbers compare as numbers, anything else as text.
+fn literal(value: &str) -> String {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    let number = digits.splitn(2, '.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    if number {
+        value.to_string()
+    } else {
+        format!("'{}'", escape(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::{default_order, tjf_order};
+
+    fn rule(column: &str, operator: Operator, value: &str, prefer: bool) -> KeeperRule {
+        KeeperRule { column: column.to_string(), operator, value: value.to_string(), prefer }
+    }
+
+    /// Built-in order lines the rule builder can't show as a rule, kept as raw lines. None so far.
+    const RAW_BUILT_IN_LINES: &[&str] = &[];
+
+    #[test]
+    fn built_in_orders_round_trip() {
+        for line in default_order().iter().chain(&tjf_order()).filter(|line| !RAW_BUILT_IN_LINES.contains(&line.as_str())) {
+            let rule = KeeperRule::parse(line).unwrap_or_else(|| panic!("built-in line doesn't parse: {}", line));
+            assert_eq!(&rule.compile(), line);
+        }
+    }
+
+    #[test]
+    fn raw_built_in_lines_are_built_in_and_unparsed() {
+        let built_in: Vec<String> = default_order().into_iter().chain(tjf_order()).collect();
+        for line in RAW_BUILT_IN_LINES {
+            assert!(built_in.iter().any(|built_in| built_in == line), "not a built-in line: {}", line);
+            assert_eq!(KeeperRule::parse(line), None, "raw line parses now: {}", line);
+        }
+    }
+
+    #[test]
+    fn every_operator_round_trips() {
+        for operator in Operator::ALL {
+            for prefer in [true, false] {
+                for value in ["", "Audio Files", "10"] {
+                    let line = rule("pathname", operator, if operator.takes_value() { value } else { "" }, prefer).compile();
+                    // Avoiding the greatest sorts like preferring the least, so only the SQL is compared
+                    assert_eq!(KeeperRule::parse(&line).map(|rule| rule.compile()), Some(line));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn values_with_quotes_round_trip() {
+        let contains = rule("Description", Operator::Contains, "Dog's \"bark\"", true);
+        assert_eq!(contains.compile(), "CASE WHEN Description LIKE '%Dog''s \"bark\"%' THEN 0 ELSE 1 END ASC");
+        assert_eq!(KeeperRule::parse(&contains.compile()), Some(contains));
+
+        let greater = rule("Library", Operator::Greater, "O'Brien", false);
+        assert_eq!(greater.compile(), "CASE WHEN Library > 'O''Brien' THEN 1 ELSE 0 END ASC");
+        assert_eq!(KeeperRule::parse(&greater.compile()), Some(greater));
+    }
+
+    #[test]
+    fn columns_that_need_quoting_round_trip() {
+        let spaced = rule("Sample Rate", Operator::Less, "", true);
+        assert_eq!(spaced.compile(), "\"Sample Rate\" ASC");
+        assert_eq!(KeeperRule::parse(&spaced.compile()), Some(spaced));
+    }
+
+    #[test]
+    fn other_sql_stays_raw() {
+        assert_eq!(KeeperRule::parse("CASE WHEN pathname LIKE '%a%' THEN 2 ELSE 0 END ASC"), None);
+        assert_eq!(KeeperRule::parse("CASE WHEN a IS NOT NULL AND b != '' THEN 0 ELSE 1 END ASC"), None);
+        assert_eq!(KeeperRule::parse("length(pathname) DESC"), None);
+    }
+
+    #[test]
+    fn literal_compares_plain_numbers_as_numbers() {
+        assert_eq!(literal("10"), "10");
+        assert_eq!(literal("-2.5"), "-2.5");
+        assert_eq!(literal("0.25"), "0.25");
+        assert_eq!(literal("1."), "'1.'");
+        assert_eq!(literal(".5"), "'.5'");
+        assert_eq!(literal("-"), "'-'");
+        assert_eq!(literal("1e3"), "'1e3'");
+        assert_eq!(literal("1.2.3"), "'1.2.3'");
+        assert_eq!(literal("it's"), "'it''s'");
+        assert_eq!(literal(""), "''");
+    }
+}
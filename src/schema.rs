@@ -0,0 +1,87 @@
+use rusqlite::Connection;
+use crate::error::DbError;
+use crate::processing::{open_soundminer, table_columns};
+
+/// The parts of the app that rely on particular `justinmetadata` columns.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Feature { Duplicates, DeepDive, Tags, Compare, Replace, Dirty }
+
+impl Feature {
+    pub const ALL: [Feature; 6] = [Feature::Duplicates, Feature::DeepDive, Feature::Tags, Feature::Compare, Feature::Replace, Feature::Dirty];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Duplicates => "Duplicate Filename Search",
+            Feature::DeepDive => "Deep Dive Search",
+            Feature::Tags => "Tag Search",
+            Feature::Compare => "Database Comparison",
+            Feature::Replace => "Find and Replace",
+            Feature::Dirty => "Mark Records as Dirty",
+        }
+    }
+
+    /// Columns the feature always uses. Columns the user picks, like a group or match column,
+    /// are checked when the feature runs.
+    pub fn columns(self) -> &'static [&'static str] {
+        match self {
+            // The searches that rank also need the keeper order's columns, checked when the search runs
+            Feature::Duplicates | Feature::DeepDive | Feature::Tags | Feature::Compare => &["filename", "duration"],
+            Feature::Replace => &["filename"],
+            Feature::Dirty => &["_Dirty"],
+        }
+    }
+}
+
+/// What an opened database offers, read once on open so missing columns disable features up front.
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    pub columns: Vec<String>,
+}
+
+impl Schema {
+    pub fn read(conn: &Connection) -> Result<Self, DbError> {
+        Ok(Self { columns: table_columns(conn, "main")? })
+    }
+
+    /// SQLite matches column names without regard to case, so this does too.
+    pub fn has_column(&self, column: &str) -> bool {
+        self.columns.iter().any(|c| c.eq_ignore_ascii_case(column))
+    }
+
+    pub fn missing(&self, feature: Feature) -> Vec<&'static str> {
+        feature.columns().iter().copied().filter(|column| !self.has_column(column)).collect()
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.missing(feature).is_empty()
+    }
+
+    pub fn require(&self, feature: Feature) -> Result<(), DbError> {
+        let missing = self.missing(feature);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(DbError::MissingColumns(feature, missing))
+        }
+    }
+
+    /// Every expected column the database lacks, each listed once.
+    pub fn missing_columns(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        for column in Feature::ALL.iter().flat_map(|feature| self.missing(*feature)) {
+            if !missing.contains(&column) {
+                missing.push(column);
+            }
+        }
+        missing
+    }
+
+    pub fn disabled(&self) -> Vec<Feature> {
+        Feature::ALL.into_iter().filter(|feature| !self.supports(*feature)).collect()
+    }
+}
+
+pub fn get_schema(db_path: &str) -> Result<Schema, DbError> {
+    let conn = open_soundminer(db_path)?;
+    Schema::read(&conn)
+}
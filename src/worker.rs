@@ -0,0 +1,65 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use eframe::egui;
+use crate::report::{Cancel, Message, Reporter, Stage};
+
+pub struct Job {
+    receiver: Receiver<Message>,
+    cancel: Arc<Cancel>,
+}
+
+impl Job {
+    /// Runs `task` on a worker thread, requesting a repaint of `ctx` as each message is sent.
+    pub fn spawn<F>(ctx: &egui::Context, task: F) -> Self
+    where
+        F: FnOnce(&Reporter) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let ctx = ctx.clone();
+        let reporter = Reporter::new(sender).on_send(move || ctx.request_repaint());
+        let cancel = reporter.cancel();
+        thread::spawn(move || {
+            // A panic would otherwise end the job without a word, the UI still learns it is done
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| task(&reporter))) {
+                let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                reporter.status(Stage::Main, format!("Background job failed: {}", message));
+            }
+            reporter.send(Message::Done);
+        });
+        Self { receiver, cancel }
+    }
+
+    /// Asks the job to stop at its next check, interrupting any statement it is waiting on.
+    pub fn cancel(&self) {
+        self.cancel.request();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_requested()
+    }
+
+    /// Returns every message received since the last poll.
+    /// A job whose thread died without finishing is reported as `Done`.
+    pub fn poll(&self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(message) => messages.push(message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if !matches!(messages.last(), Some(Message::Done)) {
+                        messages.push(Message::Status(Stage::Main, "Background job stopped unexpectedly".to_string()));
+                        messages.push(Message::Done);
+                    }
+                    break;
+                }
+            }
+        }
+        messages
+    }
+}